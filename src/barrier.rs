@@ -0,0 +1,581 @@
+//! Barriers for phased synchronization, built on the crate's non-poisoning
+//! [`Mutex`] and [`Condvar`].
+//!
+//! A party whose deadline passes, or whose cancellation note is notified,
+//! before the others arrive withdraws its arrival, so the remaining parties
+//! are not released early and the barrier stays usable.
+
+use crate::nopoison::{Condvar, Mutex};
+use crate::note::{Note, WaitOutcome};
+use crate::time::Time;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Duration as StdDuration;
+
+/// A barrier that lets a fixed number of parties wait for each other, in the
+/// style of `std::sync::Barrier`. It can be reused once all parties have
+/// passed.
+///
+/// ```
+/// use nsync_rs::Barrier;
+/// use std::sync::Arc;
+///
+/// let barrier = Arc::new(Barrier::new(4));
+/// let threads: Vec<_> = (0..4)
+///     .map(|_| {
+///         let barrier = barrier.clone();
+///         std::thread::spawn(move || barrier.wait().is_leader())
+///     })
+///     .collect();
+/// let leaders = threads.into_iter().filter_map(|t| t.join().ok()).filter(|&l| l);
+/// assert_eq!(leaders.count(), 1);
+/// ```
+pub struct Barrier {
+    state: Mutex<BarrierState>,
+    cv: Condvar,
+    parties: usize,
+}
+
+struct BarrierState {
+    arrived: usize,
+    generation: u64,
+}
+
+/// Returned by a barrier wait once every party has arrived.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BarrierWaitResult {
+    leader: bool,
+    generation: u64,
+}
+
+impl BarrierWaitResult {
+    /// Returns `true` for exactly one party of each generation: the last to
+    /// arrive.
+    pub fn is_leader(&self) -> bool {
+        self.leader
+    }
+
+    /// Returns the generation the parties passed, counting from zero.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+impl Barrier {
+    /// Creates a barrier that releases `parties` parties at a time.
+    ///
+    /// A barrier for zero or one parties never blocks.
+    pub const fn new(parties: usize) -> Barrier {
+        Barrier {
+            state: Mutex::new(BarrierState {
+                arrived: 0,
+                generation: 0,
+            }),
+            cv: Condvar::new(),
+            parties,
+        }
+    }
+
+    /// Blocks until all parties have called `wait`.
+    pub fn wait(&self) -> BarrierWaitResult {
+        match self.wait_until(Time::no_deadline(), None) {
+            Ok(result) => result,
+            Err(_) => unreachable!("barrier wait without a deadline gave up"),
+        }
+    }
+
+    /// Blocks until all parties have arrived, `deadline` passes or `cancel`
+    /// is notified. If the wait gives up, this party's arrival is withdrawn.
+    pub fn wait_until(
+        &self,
+        deadline: Time,
+        cancel: Option<&Note>,
+    ) -> Result<BarrierWaitResult, WaitOutcome> {
+        self.arrive(deadline, cancel, || {})
+    }
+
+    /// Blocks until all parties have arrived or `timeout` elapses. If the
+    /// wait times out, this party's arrival is withdrawn.
+    pub fn wait_timeout(&self, timeout: StdDuration) -> Result<BarrierWaitResult, WaitOutcome> {
+        self.wait_until(Time::now() + timeout.into(), None)
+    }
+
+    /// Returns the number of parties the barrier releases at a time.
+    pub fn parties(&self) -> usize {
+        self.parties
+    }
+
+    /// Returns the number of parties waiting for the current generation.
+    pub fn waiting(&self) -> usize {
+        self.state.lock().arrived
+    }
+
+    /// Returns the number of generations that have passed.
+    pub fn generation(&self) -> u64 {
+        self.state.lock().generation
+    }
+
+    /// Arrives at the barrier. The last party to arrive runs `trip` before
+    /// releasing the others; if `trip` panics, the others are still
+    /// released and the panic resumes in the last party.
+    fn arrive(
+        &self,
+        deadline: Time,
+        cancel: Option<&Note>,
+        trip: impl FnOnce(),
+    ) -> Result<BarrierWaitResult, WaitOutcome> {
+        let mut state = self.state.lock();
+        let generation = state.generation;
+        state.arrived += 1;
+        if state.arrived < self.parties {
+            while state.generation == generation {
+                let outcome = self.cv.wait_until(&mut state, deadline, cancel);
+                if outcome != WaitOutcome::Completed && state.generation == generation {
+                    state.arrived -= 1;
+                    return Err(outcome);
+                }
+            }
+            return Ok(BarrierWaitResult {
+                leader: false,
+                generation,
+            });
+        }
+
+        let result = panic::catch_unwind(AssertUnwindSafe(trip));
+        state.arrived = 0;
+        state.generation += 1;
+        self.cv.notify_all();
+        drop(state);
+        if let Err(payload) = result {
+            panic::resume_unwind(payload);
+        }
+        Ok(BarrierWaitResult {
+            leader: true,
+            generation,
+        })
+    }
+}
+
+impl fmt::Debug for Barrier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock();
+        f.debug_struct("Barrier")
+            .field("parties", &self.parties)
+            .field("waiting", &state.arrived)
+            .field("generation", &state.generation)
+            .finish()
+    }
+}
+
+/// A reusable barrier that runs an action each time all parties arrive.
+///
+/// The last party to arrive runs the action before any party is released,
+/// so the action sees the results of the generation that just finished. The
+/// action must not call back into its barrier.
+pub struct CyclicBarrier {
+    barrier: Barrier,
+    action: Mutex<Box<dyn FnMut() + Send>>,
+}
+
+impl CyclicBarrier {
+    /// Creates a barrier that releases `parties` parties at a time, after
+    /// running `action`.
+    pub fn new(parties: usize, action: impl FnMut() + Send + 'static) -> CyclicBarrier {
+        CyclicBarrier {
+            barrier: Barrier::new(parties),
+            action: Mutex::new(Box::new(action)),
+        }
+    }
+
+    /// Blocks until all parties have called `wait`.
+    ///
+    /// # Panics
+    ///
+    /// If the barrier action panics, the other parties are released and the
+    /// panic propagates to the party that ran it.
+    pub fn wait(&self) -> BarrierWaitResult {
+        match self.wait_until(Time::no_deadline(), None) {
+            Ok(result) => result,
+            Err(_) => unreachable!("barrier wait without a deadline gave up"),
+        }
+    }
+
+    /// Blocks until all parties have arrived, `deadline` passes or `cancel`
+    /// is notified. If the wait gives up, this party's arrival is withdrawn.
+    pub fn wait_until(
+        &self,
+        deadline: Time,
+        cancel: Option<&Note>,
+    ) -> Result<BarrierWaitResult, WaitOutcome> {
+        self.barrier
+            .arrive(deadline, cancel, || (self.action.lock())())
+    }
+
+    /// Blocks until all parties have arrived or `timeout` elapses. If the
+    /// wait times out, this party's arrival is withdrawn.
+    pub fn wait_timeout(&self, timeout: StdDuration) -> Result<BarrierWaitResult, WaitOutcome> {
+        self.wait_until(Time::now() + timeout.into(), None)
+    }
+
+    /// Returns the number of parties the barrier releases at a time.
+    pub fn parties(&self) -> usize {
+        self.barrier.parties()
+    }
+
+    /// Returns the number of parties waiting for the current generation.
+    pub fn waiting(&self) -> usize {
+        self.barrier.waiting()
+    }
+
+    /// Returns the number of generations that have passed.
+    pub fn generation(&self) -> u64 {
+        self.barrier.generation()
+    }
+}
+
+impl fmt::Debug for CyclicBarrier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.barrier.state.lock();
+        f.debug_struct("CyclicBarrier")
+            .field("parties", &self.barrier.parties)
+            .field("waiting", &state.arrived)
+            .field("generation", &state.generation)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A reusable barrier whose parties can register and deregister between
+/// phases, in the style of Java's `Phaser`.
+///
+/// Each phase ends when every registered party has arrived. A party that
+/// registers during a phase takes part in it, and one that deregisters no
+/// longer holds it up.
+///
+/// ```
+/// use nsync_rs::Phaser;
+/// use std::sync::Arc;
+///
+/// let phaser = Arc::new(Phaser::new(1));
+/// let workers: Vec<_> = (0..3)
+///     .map(|_| {
+///         phaser.register();
+///         let phaser = phaser.clone();
+///         std::thread::spawn(move || {
+///             for _ in 0..2 {
+///                 phaser.arrive_and_wait();
+///             }
+///             phaser.arrive_and_deregister();
+///         })
+///     })
+///     .collect();
+/// phaser.arrive_and_deregister();
+/// for worker in workers {
+///     worker.join().unwrap();
+/// }
+/// ```
+pub struct Phaser {
+    state: Mutex<PhaserState>,
+    cv: Condvar,
+}
+
+struct PhaserState {
+    parties: usize,
+    arrived: usize,
+    phase: u64,
+}
+
+impl Phaser {
+    /// Creates a phaser with `parties` registered parties, at phase zero.
+    pub const fn new(parties: usize) -> Phaser {
+        Phaser {
+            state: Mutex::new(PhaserState {
+                parties,
+                arrived: 0,
+                phase: 0,
+            }),
+            cv: Condvar::new(),
+        }
+    }
+
+    /// Registers a new party, returning the phase it first arrives at.
+    pub fn register(&self) -> u64 {
+        let mut state = self.state.lock();
+        state.parties += 1;
+        state.phase
+    }
+
+    /// Arrives at the current phase without waiting for the other parties,
+    /// returning the phase arrived at.
+    ///
+    /// # Panics
+    ///
+    /// Panics if every registered party has already arrived.
+    pub fn arrive(&self) -> u64 {
+        let mut state = self.state.lock();
+        let phase = state.phase;
+        self.count_arrival(&mut state);
+        phase
+    }
+
+    /// Deregisters one party without waiting for the others, returning the
+    /// phase it left at. The current phase ends if the remaining parties
+    /// have all arrived.
+    ///
+    /// # Panics
+    ///
+    /// Panics if every registered party has already arrived.
+    pub fn arrive_and_deregister(&self) -> u64 {
+        let mut state = self.state.lock();
+        let phase = state.phase;
+        assert!(
+            state.arrived < state.parties,
+            "Phaser has more arrivals than registered parties"
+        );
+        state.parties -= 1;
+        if state.arrived == state.parties {
+            self.advance(&mut state);
+        }
+        phase
+    }
+
+    /// Arrives at the current phase and blocks until every party has
+    /// arrived, returning the new phase.
+    ///
+    /// # Panics
+    ///
+    /// Panics if every registered party has already arrived.
+    pub fn arrive_and_wait(&self) -> u64 {
+        match self.arrive_and_wait_until(Time::no_deadline(), None) {
+            Ok(phase) => phase,
+            Err(_) => unreachable!("phaser wait without a deadline gave up"),
+        }
+    }
+
+    /// Arrives at the current phase and blocks until every party has
+    /// arrived, `deadline` passes or `cancel` is notified. If the wait gives
+    /// up, this party's arrival is withdrawn.
+    ///
+    /// # Panics
+    ///
+    /// Panics if every registered party has already arrived.
+    pub fn arrive_and_wait_until(
+        &self,
+        deadline: Time,
+        cancel: Option<&Note>,
+    ) -> Result<u64, WaitOutcome> {
+        let mut state = self.state.lock();
+        let phase = state.phase;
+        self.count_arrival(&mut state);
+        while state.phase == phase {
+            let outcome = self.cv.wait_until(&mut state, deadline, cancel);
+            if outcome != WaitOutcome::Completed && state.phase == phase {
+                state.arrived -= 1;
+                return Err(outcome);
+            }
+        }
+        Ok(state.phase)
+    }
+
+    /// Blocks until the phaser has moved past `phase`, returning the new
+    /// phase. Returns at once if it already has.
+    pub fn await_advance(&self, phase: u64) -> u64 {
+        match self.await_advance_until(phase, Time::no_deadline(), None) {
+            Ok(phase) => phase,
+            Err(_) => unreachable!("phaser wait without a deadline gave up"),
+        }
+    }
+
+    /// Blocks until the phaser has moved past `phase`, `deadline` passes or
+    /// `cancel` is notified.
+    pub fn await_advance_until(
+        &self,
+        phase: u64,
+        deadline: Time,
+        cancel: Option<&Note>,
+    ) -> Result<u64, WaitOutcome> {
+        let mut state = self.state.lock();
+        while state.phase == phase {
+            let outcome = self.cv.wait_until(&mut state, deadline, cancel);
+            if outcome != WaitOutcome::Completed && state.phase == phase {
+                return Err(outcome);
+            }
+        }
+        Ok(state.phase)
+    }
+
+    /// Returns the current phase.
+    pub fn phase(&self) -> u64 {
+        self.state.lock().phase
+    }
+
+    /// Returns the number of registered parties.
+    pub fn registered_parties(&self) -> usize {
+        self.state.lock().parties
+    }
+
+    /// Returns the number of parties that have arrived at the current phase.
+    pub fn arrived_parties(&self) -> usize {
+        self.state.lock().arrived
+    }
+
+    fn count_arrival(&self, state: &mut PhaserState) {
+        assert!(
+            state.arrived < state.parties,
+            "Phaser has more arrivals than registered parties"
+        );
+        state.arrived += 1;
+        if state.arrived == state.parties {
+            self.advance(state);
+        }
+    }
+
+    fn advance(&self, state: &mut PhaserState) {
+        state.arrived = 0;
+        state.phase += 1;
+        self.cv.notify_all();
+    }
+}
+
+impl fmt::Debug for Phaser {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock();
+        f.debug_struct("Phaser")
+            .field("phase", &state.phase)
+            .field("parties", &state.parties)
+            .field("arrived", &state.arrived)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn one_leader_per_generation() {
+        let barrier = Barrier::new(3);
+        let leaders = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..3 {
+                s.spawn(|| {
+                    for generation in 0..5 {
+                        let result = barrier.wait();
+                        assert_eq!(result.generation(), generation);
+                        if result.is_leader() {
+                            leaders.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(leaders.load(Ordering::Relaxed), 5);
+        assert_eq!(barrier.generation(), 5);
+    }
+
+    #[test]
+    fn a_single_party_never_blocks() {
+        let barrier = Barrier::new(1);
+        assert!(barrier.wait().is_leader());
+        assert!(Barrier::new(0).wait().is_leader());
+    }
+
+    #[test]
+    fn a_timed_out_party_withdraws_its_arrival() {
+        let barrier = Barrier::new(2);
+        assert_eq!(
+            barrier.wait_timeout(StdDuration::from_millis(10)),
+            Err(WaitOutcome::TimedOut)
+        );
+        assert_eq!(barrier.waiting(), 0);
+        assert_eq!(barrier.generation(), 0);
+        thread::scope(|s| {
+            s.spawn(|| barrier.wait());
+            barrier.wait();
+        });
+        assert_eq!(barrier.generation(), 1);
+    }
+
+    #[test]
+    fn a_cancelled_party_withdraws_its_arrival() {
+        let barrier = Barrier::new(2);
+        let cancel = Note::new(None, Time::no_deadline());
+        cancel.notify();
+        assert_eq!(
+            barrier.wait_until(Time::no_deadline(), Some(&cancel)),
+            Err(WaitOutcome::Cancelled)
+        );
+        assert_eq!(barrier.waiting(), 0);
+    }
+
+    #[test]
+    fn cyclic_barrier_runs_its_action_before_releasing() {
+        let trips = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&trips);
+        let barrier = CyclicBarrier::new(2, move || {
+            counted.fetch_add(1, Ordering::Relaxed);
+        });
+        thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for generation in 0..3 {
+                        barrier.wait();
+                        assert!(trips.load(Ordering::Relaxed) > generation);
+                    }
+                });
+            }
+        });
+        assert_eq!(trips.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn a_panicking_action_still_releases_the_others() {
+        let barrier = CyclicBarrier::new(2, || panic!("action failed"));
+        thread::scope(|s| {
+            let other = s.spawn(|| panic::catch_unwind(AssertUnwindSafe(|| barrier.wait())));
+            let mine = panic::catch_unwind(AssertUnwindSafe(|| barrier.wait()));
+            let other = other.join().unwrap();
+            // Exactly one party ran the action and saw its panic.
+            assert_ne!(mine.is_err(), other.is_err());
+        });
+        assert_eq!(barrier.generation(), 1);
+    }
+
+    #[test]
+    fn phaser_tracks_registrations() {
+        let phaser = Phaser::new(1);
+        assert_eq!(phaser.register(), 0);
+        assert_eq!(phaser.arrive(), 0);
+        assert_eq!(phaser.arrived_parties(), 1);
+        assert_eq!(phaser.arrive_and_deregister(), 0);
+        assert_eq!(phaser.phase(), 1);
+        assert_eq!(phaser.registered_parties(), 1);
+        assert_eq!(phaser.await_advance(0), 1);
+        assert_eq!(phaser.arrive_and_wait(), 2);
+    }
+
+    #[test]
+    fn phaser_waits_for_every_party_and_withdraws_on_timeout() {
+        let phaser = Phaser::new(2);
+        assert_eq!(
+            phaser.arrive_and_wait_until(Time::now() + StdDuration::from_millis(10).into(), None),
+            Err(WaitOutcome::TimedOut)
+        );
+        assert_eq!(phaser.arrived_parties(), 0);
+        thread::scope(|s| {
+            let waiter = s.spawn(|| phaser.await_advance(0));
+            s.spawn(|| phaser.arrive_and_wait());
+            assert_eq!(phaser.arrive_and_wait(), 1);
+            assert_eq!(waiter.join().unwrap(), 1);
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "more arrivals than registered parties")]
+    fn phaser_rejects_extra_arrivals() {
+        let phaser = Phaser::new(0);
+        phaser.arrive();
+    }
+}
@@ -0,0 +1,104 @@
+//! Trampolines for passing Rust closures to nsync as C callbacks.
+//!
+//! A panic must never unwind through nsync's C frames. Each trampoline runs
+//! its closure under `catch_unwind`; if the closure panics, the trampoline
+//! stores the payload, runs the callback's `on_panic` hook (which typically
+//! poisons the lock the callback belongs to) and returns normally, so nsync
+//! unwinds its own state and unlocks as usual. Once the nsync call has
+//! returned to Rust, the caller restores its own invariants and calls
+//! [`Callback::resume`] to carry on with the panic.
+//!
+//! After a panic the closure is not called again: predicates report true so
+//! that a waiter wakes promptly, and other callbacks do nothing.
+
+use std::any::Any;
+use std::cell::UnsafeCell;
+use std::os::raw::{c_int, c_void};
+use std::panic::{self, AssertUnwindSafe};
+
+/// A Rust closure, and the state its trampolines share with the caller.
+///
+/// nsync may call a callback from a thread other than the one that passed
+/// it in, but never from two threads at once: conditions are evaluated with
+/// the mutex held, and other callbacks run on the calling thread.
+pub(crate) struct Callback<F, P> {
+    f: UnsafeCell<F>,
+    on_panic: UnsafeCell<P>,
+    panic: UnsafeCell<Option<Box<dyn Any + Send>>>,
+}
+
+impl<F, P: FnMut()> Callback<F, P> {
+    /// Creates a callback that runs `on_panic` right after catching a panic
+    /// from `f`, before control returns to nsync. `on_panic` must not panic.
+    pub(crate) fn new(f: F, on_panic: P) -> Callback<F, P> {
+        Callback {
+            f: UnsafeCell::new(f),
+            on_panic: UnsafeCell::new(on_panic),
+            panic: UnsafeCell::new(None),
+        }
+    }
+
+    /// The argument to pass to nsync along with one of the trampolines.
+    pub(crate) fn arg(&self) -> *mut c_void {
+        self as *const Callback<F, P> as *mut c_void
+    }
+
+    /// Returns whether the closure has panicked.
+    pub(crate) fn panicked(&self) -> bool {
+        unsafe { (*self.panic.get()).is_some() }
+    }
+
+    /// Resumes the closure's panic, if it panicked.
+    pub(crate) fn resume(self) {
+        if let Some(payload) = self.panic.into_inner() {
+            panic::resume_unwind(payload);
+        }
+    }
+
+    /// Runs `call` on the closure unless it has already panicked, catching
+    /// any new panic.
+    ///
+    /// # Safety
+    ///
+    /// No other call may be running on this callback.
+    unsafe fn run<R>(&self, call: impl FnOnce(&mut F) -> R) -> Option<R> {
+        if self.panicked() {
+            return None;
+        }
+        let f = unsafe { &mut *self.f.get() };
+        match panic::catch_unwind(AssertUnwindSafe(|| call(f))) {
+            Ok(result) => Some(result),
+            Err(payload) => {
+                unsafe {
+                    *self.panic.get() = Some(payload);
+                    (*self.on_panic.get())();
+                }
+                None
+            }
+        }
+    }
+}
+
+impl<F: FnMut(), P: FnMut()> Callback<F, P> {
+    /// The trampoline for `void (*)(void *)` callbacks.
+    pub(crate) fn void_fn(&self) -> unsafe extern "C" fn(*mut c_void) {
+        unsafe extern "C" fn trampoline<F: FnMut(), P: FnMut()>(arg: *mut c_void) {
+            let callback = unsafe { &*(arg as *const Callback<F, P>) };
+            unsafe { callback.run(|f| f()) };
+        }
+        trampoline::<F, P>
+    }
+}
+
+impl<F: FnMut() -> bool, P: FnMut()> Callback<F, P> {
+    /// The trampoline for `int (*)(const void *)` conditions.
+    pub(crate) fn condition_fn(&self) -> unsafe extern "C" fn(*const c_void) -> c_int {
+        unsafe extern "C" fn trampoline<F: FnMut() -> bool, P: FnMut()>(
+            arg: *const c_void,
+        ) -> c_int {
+            let callback = unsafe { &*(arg as *const Callback<F, P>) };
+            unsafe { callback.run(|f| f()) }.unwrap_or(true) as c_int
+        }
+        trampoline::<F, P>
+    }
+}
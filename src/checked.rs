@@ -0,0 +1,195 @@
+//! Misuse detection, enabled by the `checked` feature.
+//!
+//! nsync silently tolerates, or aborts on, several mistakes that tend to show
+//! up as hangs far from their cause. With the feature enabled they panic at
+//! the point of misuse instead, with a message and a backtrace.
+
+#[cfg(feature = "checked")]
+use crate::nopoison;
+#[cfg(feature = "checked")]
+use std::{
+    backtrace::Backtrace,
+    cell::RefCell,
+    sync::Arc,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Reports a misuse by panicking with `message` and the current backtrace.
+#[cfg(feature = "checked")]
+#[cold]
+fn violation(message: String) -> ! {
+    panic!(
+        "nsync misuse: {message}\n\nbacktrace:\n{}",
+        Backtrace::force_capture()
+    );
+}
+
+#[cfg(feature = "checked")]
+thread_local! {
+    /// The locks the current thread holds, by the address of their tracker.
+    static HELD: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Called once the current thread holds the lock identified by `lock`.
+#[inline]
+pub(crate) fn acquired(lock: usize) {
+    #[cfg(feature = "checked")]
+    let _ = HELD.try_with(|held| held.borrow_mut().push(lock));
+    #[cfg(not(feature = "checked"))]
+    let _ = lock;
+}
+
+/// Called when the current thread releases the lock identified by `lock`.
+#[inline]
+pub(crate) fn released(lock: usize) {
+    #[cfg(feature = "checked")]
+    let _ = HELD.try_with(|held| {
+        let mut held = held.borrow_mut();
+        if let Some(i) = held.iter().rposition(|&l| l == lock) {
+            held.remove(i);
+        }
+    });
+    #[cfg(not(feature = "checked"))]
+    let _ = lock;
+}
+
+/// The mutex a condition variable's current waiters use. Compiles to
+/// nothing without the `checked` feature.
+pub(crate) struct CondvarMutex {
+    /// The mutex's tracker address, and the number of threads waiting with
+    /// it. The binding is dropped when the last waiter returns, so a mutex
+    /// that was moved or recreated can be used next.
+    #[cfg(feature = "checked")]
+    state: nopoison::Mutex<(usize, usize)>,
+}
+
+impl CondvarMutex {
+    pub(crate) const fn new() -> CondvarMutex {
+        CondvarMutex {
+            #[cfg(feature = "checked")]
+            state: nopoison::Mutex::new((0, 0)),
+        }
+    }
+
+    /// Called before the condition variable `cv` waits with the mutex
+    /// identified by `mutex`. All threads waiting at the same time must use
+    /// the same mutex, and the current thread must not hold any other lock
+    /// while it waits.
+    #[inline]
+    #[cfg_attr(not(feature = "checked"), allow(unused_variables))]
+    pub(crate) fn before_wait(&self, mutex: usize, cv: impl FnOnce() -> String) {
+        #[cfg(feature = "checked")]
+        {
+            let others = HELD
+                .try_with(|held| held.borrow().iter().filter(|&&l| l != mutex).count())
+                .unwrap_or(0);
+            if others > 0 {
+                violation(format!(
+                    "waiting on {} while holding {others} other lock(s), which stay locked for the \
+                     whole wait",
+                    cv()
+                ));
+            }
+            let mut state = self.state.lock();
+            let (bound, waiters) = *state;
+            if waiters > 0 && bound != mutex {
+                drop(state);
+                violation(format!(
+                    "{} used with two different mutexes ({bound:#x} and {mutex:#x})",
+                    cv()
+                ));
+            }
+            *state = (mutex, waiters + 1);
+        }
+    }
+
+    /// Called once a wait that passed [`before_wait`](Self::before_wait)
+    /// has returned.
+    #[inline]
+    pub(crate) fn after_wait(&self) {
+        #[cfg(feature = "checked")]
+        {
+            self.state.lock().1 -= 1;
+        }
+    }
+}
+
+/// Called before adding `delta` to a counter whose value is `value()`, and
+/// which has been waited on if `waited` is set.
+#[inline]
+#[cfg_attr(not(feature = "checked"), allow(unused_variables))]
+pub(crate) fn counter_add(value: impl FnOnce() -> u32, delta: i32, waited: bool) {
+    #[cfg(feature = "checked")]
+    if delta != 0 {
+        let value = value();
+        let new = value as i64 + delta as i64;
+        if new < 0 {
+            violation(format!(
+                "Counter::add({delta}) would take the counter from {value} below zero"
+            ));
+        } else if new > i32::MAX as i64 {
+            violation(format!(
+                "Counter::add({delta}) would take the counter from {value} past i32::MAX"
+            ));
+        } else if delta > 0 && waited && value == 0 {
+            violation(format!(
+                "Counter::add({delta}) would raise the counter from zero after it was waited on"
+            ));
+        }
+    }
+}
+
+/// A note's live children. Compiles to nothing without the `checked`
+/// feature.
+///
+/// nsync lets a note be freed while it has children: they are adopted by
+/// its parent, and no longer expire with the freed note's deadline. Freeing
+/// a note before its children is almost always a bug.
+pub(crate) struct NoteChildren {
+    #[cfg(feature = "checked")]
+    children: Arc<AtomicUsize>,
+    /// The parent's count, which includes this note.
+    #[cfg(feature = "checked")]
+    parent: Option<Arc<AtomicUsize>>,
+}
+
+impl NoteChildren {
+    #[cfg_attr(not(feature = "checked"), allow(unused_variables))]
+    pub(crate) fn new(parent: Option<&NoteChildren>) -> NoteChildren {
+        #[cfg(feature = "checked")]
+        {
+            let parent = parent.map(|p| {
+                p.children.fetch_add(1, Ordering::Relaxed);
+                p.children.clone()
+            });
+            NoteChildren {
+                children: Arc::new(AtomicUsize::new(0)),
+                parent,
+            }
+        }
+        #[cfg(not(feature = "checked"))]
+        NoteChildren {}
+    }
+
+    /// Called before the note is freed. Returns whether it may be.
+    #[inline]
+    pub(crate) fn before_free(&self) -> bool {
+        #[cfg(feature = "checked")]
+        {
+            let children = self.children.load(Ordering::Relaxed);
+            if children > 0 {
+                if !std::thread::panicking() {
+                    violation(format!(
+                        "Note freed while {children} child note(s) still exist"
+                    ));
+                }
+                // Leak the note rather than panic while unwinding.
+                return false;
+            }
+            if let Some(parent) = &self.parent {
+                parent.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+        true
+    }
+}
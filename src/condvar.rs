@@ -1,15 +1,19 @@
+use crate::debug::{self, CondvarState};
+use crate::ffi;
 use crate::mutex::{LockResult, MutexGuard};
+use crate::note::{Note, WaitOutcome};
 use crate::time::{Duration, Time};
-use crate::{PoisonError, ffi};
+use crate::track::CondvarTracker;
+use crate::track::Outcome;
 use std::cell::UnsafeCell;
-use std::fmt::Debug;
+use std::fmt;
 use std::marker::PhantomData;
-use std::mem::MaybeUninit;
 use std::time::Duration as StdDuration;
 
 /// A Condition Variable
 pub struct Condvar {
-    _inner: UnsafeCell<ffi::nsync_cv>,
+    pub(super) _inner: UnsafeCell<ffi::nsync_cv>,
+    pub(super) track: CondvarTracker,
 }
 
 unsafe impl Send for Condvar {}
@@ -18,7 +22,7 @@ unsafe impl Sync for Condvar {}
 /// A type indicating whether a timed wait on a condition variable returned
 /// due to a time out or not.
 #[derive(Debug, PartialEq, Eq, Copy, Clone)]
-pub struct WaitTimeoutResult(bool);
+pub struct WaitTimeoutResult(pub(super) bool);
 
 impl WaitTimeoutResult {
     /// Returns `true` if the wait was known to have timed out.
@@ -29,26 +33,43 @@ impl WaitTimeoutResult {
 
 impl Condvar {
     /// Creates a new condition variable which is ready to be waited on and notified.
-    pub fn new() -> Condvar {
-        let mut cv = MaybeUninit::<ffi::nsync_cv>::uninit();
-        unsafe {
-            ffi::nsync_cv_init(cv.as_mut_ptr());
-            Condvar {
-                _inner: UnsafeCell::new(cv.assume_init()),
-            }
+    ///
+    /// A zeroed `nsync_cv` is a valid initial state, so this is a `const fn`
+    /// and can be used to initialize `static` items.
+    pub const fn new() -> Condvar {
+        Condvar {
+            _inner: UnsafeCell::new(ffi::nsync_cv {
+                word: 0,
+                waiters: std::ptr::null_mut(),
+            }),
+            track: CondvarTracker::new(),
+        }
+    }
+
+    /// Creates a new condition variable registered under `name`.
+    ///
+    /// Named condition variables are listed by
+    /// [`dump_all_locks`](crate::dump_all_locks) while they are alive.
+    pub fn named(name: impl Into<String>) -> Condvar {
+        Condvar {
+            track: CondvarTracker::named(name.into()),
+            ..Condvar::new()
         }
     }
 
     /// Blocks the current thread until this condition variable receives a notification.
     pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> LockResult<MutexGuard<'a, T>> {
         let mutex = guard.lock;
+        self.track.before_wait(mutex.track.addr());
+        mutex.track.clear_owner();
         // DON'T drop the guard, nsync expects the mutex to be held
         // The wait function will unlock it internally
-        unsafe {
+        self.track.wait(self._inner.get(), || unsafe {
             // Pass the locked mutex to nsync_cv_wait
             ffi::nsync_cv_wait(self._inner.get(), mutex._inner.get());
             // nsync_cv_wait returns with the mutex locked again
-        }
+            Outcome::Woken
+        });
         std::mem::forget(guard);
         MutexGuard::new(mutex)
     }
@@ -59,37 +80,78 @@ impl Condvar {
         guard: MutexGuard<'a, T>,
         dur: StdDuration,
     ) -> LockResult<(MutexGuard<'a, T>, WaitTimeoutResult)> {
-        let mutex = guard.lock;
         let deadline = Time::now() + Duration::from(dur);
+        let (guard, outcome) = self.wait_deadline(guard, deadline, None);
+        let result = WaitTimeoutResult(outcome == Outcome::TimedOut);
+        if guard.lock.is_poisoned() {
+            Err(guard.lock.poison.error((guard, result)))
+        } else {
+            Ok((guard, result))
+        }
+    }
+
+    /// Waits on this condition variable for a notification until `deadline`
+    /// passes or `cancel` is notified, whichever comes first.
+    ///
+    /// Like [`wait`](Condvar::wait), this may wake spuriously, in which
+    /// case the outcome is [`WaitOutcome::Completed`].
+    pub fn wait_until<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        deadline: Time,
+        cancel: Option<&Note>,
+    ) -> LockResult<(MutexGuard<'a, T>, WaitOutcome)> {
+        let (guard, outcome) = self.wait_deadline(guard, deadline, cancel);
+        let outcome = outcome.wait_outcome();
+        if guard.lock.is_poisoned() {
+            Err(guard.lock.poison.error((guard, outcome)))
+        } else {
+            Ok((guard, outcome))
+        }
+    }
 
-        let result = unsafe {
+    fn wait_deadline<'a, T>(
+        &self,
+        guard: MutexGuard<'a, T>,
+        deadline: Time,
+        cancel: Option<&Note>,
+    ) -> (MutexGuard<'a, T>, Outcome) {
+        let mutex = guard.lock;
+        let cancel = cancel.map_or(std::ptr::null_mut(), Note::as_ptr);
+        self.track.before_wait(mutex.track.addr());
+        mutex.track.clear_owner();
+
+        let outcome = self.track.wait(self._inner.get(), || unsafe {
             // Pass the locked mutex - don't drop the guard first
-            ffi::nsync_cv_wait_with_deadline(
+            let result = ffi::nsync_cv_wait_with_deadline(
                 self._inner.get(),
                 mutex._inner.get(),
                 deadline.as_raw(),
-                std::ptr::null_mut(),
-            )
-        };
+                cancel,
+            );
+            Outcome::cancellable(result)
+        });
         std::mem::forget(guard);
 
-        let timed_out = result != 0;
         // The mutex is already re-locked by nsync_cv_wait_with_deadline
-        let is_poisoned = mutex.is_poisoned(std::sync::atomic::Ordering::Relaxed);
+        mutex.track.set_owner();
         let guard = MutexGuard {
             lock: mutex,
             poison: std::sync::atomic::Ordering::Relaxed,
             _marker: PhantomData,
         };
-        if is_poisoned {
-            Err(PoisonError::new((guard, WaitTimeoutResult(timed_out))))
-        } else {
-            Ok((guard, WaitTimeoutResult(timed_out)))
-        }
+        (guard, outcome)
+    }
+
+    /// Returns a snapshot of this condition variable's state and waiters, as
+    /// reported by `nsync_cv_debug_state_and_waiters`.
+    pub fn state_snapshot(&self) -> CondvarState {
+        debug::cv_state(self._inner.get())
     }
 
     /// Wakes up one blocked thread on this condvar.
     pub fn notify_one(&self) {
+        self.track.notified(false);
         unsafe {
             ffi::nsync_cv_signal(self._inner.get());
         }
@@ -97,6 +159,7 @@ impl Condvar {
 
     /// Wakes up all blocked threads on this condvar.
     pub fn notify_all(&self) {
+        self.track.notified(true);
         unsafe {
             ffi::nsync_cv_broadcast(self._inner.get());
         }
@@ -108,3 +171,11 @@ impl Default for Condvar {
         Self::new()
     }
 }
+
+impl fmt::Debug for Condvar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Condvar")
+            .field("has_waiters", &self.state_snapshot().has_waiters)
+            .finish_non_exhaustive()
+    }
+}
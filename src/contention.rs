@@ -0,0 +1,295 @@
+//! Sampled call stacks of contended lock acquisitions, in the spirit of Go's
+//! mutex profile. Enabled by the `contention-profile` feature.
+
+use crate::nopoison;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Record one in every `SAMPLE_RATE` contended acquisitions; zero disables
+/// sampling.
+static SAMPLE_RATE: AtomicU32 = AtomicU32::new(0);
+static CONTENDED: AtomicU64 = AtomicU64::new(0);
+
+/// Sampled stacks, as instruction pointers leaf first, with their sampled
+/// contention count and total wait in nanoseconds.
+type Samples = HashMap<Vec<usize>, (u64, u64)>;
+
+static SAMPLES: nopoison::Mutex<Option<Samples>> = nopoison::Mutex::new(None);
+
+/// Sets the fraction of contended acquisitions whose call stack is recorded
+/// to `1 / rate`. A rate of zero, the default, turns sampling off.
+///
+/// Capturing a stack is slow, so rates of 10 to 1000 are typical. Reported
+/// counts and wait times are scaled up by the rate.
+pub fn set_contention_sample_rate(rate: u32) {
+    SAMPLE_RATE.store(rate, Ordering::Relaxed);
+}
+
+/// Called after a contended acquisition that waited `wait_nanos`.
+pub(crate) fn record(wait_nanos: u64) {
+    let rate = SAMPLE_RATE.load(Ordering::Relaxed);
+    if rate == 0
+        || !CONTENDED
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(rate as u64)
+    {
+        return;
+    }
+    let mut stack = Vec::new();
+    backtrace::trace(|frame| {
+        stack.push(frame.ip() as usize);
+        true
+    });
+    let mut samples = SAMPLES.lock();
+    let entry = samples
+        .get_or_insert_with(HashMap::new)
+        .entry(stack)
+        .or_default();
+    entry.0 += rate as u64;
+    entry.1 += wait_nanos.saturating_mul(rate as u64);
+}
+
+/// Discards all sampled stacks.
+pub(crate) fn reset() {
+    SAMPLES.lock().take();
+}
+
+/// A resolved frame: function name, file and line.
+struct Frame {
+    name: String,
+    file: String,
+    line: u32,
+}
+
+/// Resolves `ip` to its frames, innermost inlined frame first.
+fn resolve(ip: usize) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    backtrace::resolve(ip as *mut std::ffi::c_void, |symbol| {
+        frames.push(Frame {
+            name: symbol
+                .name()
+                .map(|n| format!("{n:#}"))
+                .unwrap_or_else(|| format!("{ip:#x}")),
+            file: symbol
+                .filename()
+                .map(|f| f.display().to_string())
+                .unwrap_or_default(),
+            line: symbol.lineno().unwrap_or(0),
+        });
+    });
+    if frames.is_empty() {
+        frames.push(Frame {
+            name: format!("{ip:#x}"),
+            file: String::new(),
+            line: 0,
+        });
+    }
+    frames
+}
+
+/// Frames belonging to the stack walker and this crate's instrumentation,
+/// which would otherwise top every stack.
+fn is_internal(name: &str) -> bool {
+    name.starts_with("backtrace::")
+        || name.starts_with("nsync_rs::contention::")
+        || name.starts_with("nsync_rs::profiling::")
+        || name.starts_with("nsync_rs::track::")
+}
+
+/// A sampled stack resolved leaf first, each instruction pointer with its
+/// inlined frames, and its scaled count and wait.
+type Resolved = (Vec<(usize, Vec<Frame>)>, u64, u64);
+
+/// Returns the sampled stacks resolved to function names, with internal
+/// frames dropped.
+fn resolved_samples() -> Vec<Resolved> {
+    let samples: Vec<(Vec<usize>, (u64, u64))> = match &*SAMPLES.lock() {
+        Some(samples) => samples.iter().map(|(s, v)| (s.clone(), *v)).collect(),
+        None => Vec::new(),
+    };
+    samples
+        .into_iter()
+        .map(|(stack, (count, wait))| {
+            let frames: Vec<(usize, Vec<Frame>)> = stack
+                .into_iter()
+                .map(|ip| (ip, resolve(ip)))
+                .skip_while(|(_, frames)| frames.iter().all(|f| is_internal(&f.name)))
+                .collect();
+            (frames, count, wait)
+        })
+        .collect()
+}
+
+/// Writes the sampled contention in folded-stack format, one line per stack
+/// from root to leaf, weighted by wait time in nanoseconds.
+///
+/// The output feeds straight into `flamegraph.pl` or `inferno-flamegraph`.
+pub fn write_folded(out: &mut dyn Write) -> io::Result<()> {
+    let mut lines: HashMap<String, u64> = HashMap::new();
+    for (frames, _, wait) in resolved_samples() {
+        let names: Vec<&str> = frames
+            .iter()
+            .rev()
+            .flat_map(|(_, inlined)| inlined.iter().rev().map(|f| f.name.as_str()))
+            .collect();
+        *lines.entry(names.join(";")).or_default() += wait;
+    }
+    let mut lines: Vec<_> = lines.into_iter().collect();
+    lines.sort();
+    for (stack, wait) in lines {
+        writeln!(out, "{stack} {wait}")?;
+    }
+    Ok(())
+}
+
+/// Writes the sampled contention as an uncompressed pprof protobuf profile
+/// with `contentions/count` and `delay/nanoseconds` sample types, like Go's
+/// mutex profile.
+///
+/// `go tool pprof` reads the output as is.
+pub fn write_pprof(out: &mut dyn Write) -> io::Result<()> {
+    let mut profile = Pprof::default();
+    let contentions = profile.string("contentions");
+    let count = profile.string("count");
+    let delay = profile.string("delay");
+    let nanoseconds = profile.string("nanoseconds");
+
+    let mut body = Vec::new();
+    for (ty, unit) in [(contentions, count), (delay, nanoseconds)] {
+        let mut value_type = Vec::new();
+        field_varint(&mut value_type, 1, ty);
+        field_varint(&mut value_type, 2, unit);
+        field_bytes(&mut body, 1, &value_type);
+    }
+
+    for (frames, count, wait) in resolved_samples() {
+        let locations: Vec<u64> = frames
+            .iter()
+            .map(|(ip, inlined)| profile.location(*ip, inlined))
+            .collect();
+        let mut sample = Vec::new();
+        field_packed(&mut sample, 1, &locations);
+        field_packed(&mut sample, 2, &[count, wait]);
+        field_bytes(&mut body, 2, &sample);
+    }
+
+    for location in &profile.locations {
+        field_bytes(&mut body, 4, location);
+    }
+    for function in &profile.functions {
+        field_bytes(&mut body, 5, function);
+    }
+    for s in &profile.strings {
+        field_bytes(&mut body, 6, s.as_bytes());
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    field_varint(&mut body, 9, now);
+    let mut period_type = Vec::new();
+    field_varint(&mut period_type, 1, contentions);
+    field_varint(&mut period_type, 2, count);
+    field_bytes(&mut body, 11, &period_type);
+    field_varint(
+        &mut body,
+        12,
+        SAMPLE_RATE.load(Ordering::Relaxed).max(1) as u64,
+    );
+
+    out.write_all(&body)
+}
+
+/// Interned strings, functions and locations of a pprof profile.
+#[derive(Default)]
+struct Pprof {
+    strings: Vec<String>,
+    string_ids: HashMap<String, u64>,
+    functions: Vec<Vec<u8>>,
+    function_ids: HashMap<(String, String), u64>,
+    locations: Vec<Vec<u8>>,
+    location_ids: HashMap<usize, u64>,
+}
+
+impl Pprof {
+    fn string(&mut self, s: &str) -> u64 {
+        if self.strings.is_empty() {
+            // Index zero must be the empty string.
+            self.strings.push(String::new());
+            self.string_ids.insert(String::new(), 0);
+        }
+        if let Some(&id) = self.string_ids.get(s) {
+            return id;
+        }
+        let id = self.strings.len() as u64;
+        self.strings.push(s.to_string());
+        self.string_ids.insert(s.to_string(), id);
+        id
+    }
+
+    fn function(&mut self, frame: &Frame) -> u64 {
+        let key = (frame.name.clone(), frame.file.clone());
+        if let Some(&id) = self.function_ids.get(&key) {
+            return id;
+        }
+        let id = self.functions.len() as u64 + 1;
+        let name = self.string(&frame.name);
+        let file = self.string(&frame.file);
+        let mut function = Vec::new();
+        field_varint(&mut function, 1, id);
+        field_varint(&mut function, 2, name);
+        field_varint(&mut function, 3, name);
+        field_varint(&mut function, 4, file);
+        self.functions.push(function);
+        self.function_ids.insert(key, id);
+        id
+    }
+
+    fn location(&mut self, ip: usize, inlined: &[Frame]) -> u64 {
+        if let Some(&id) = self.location_ids.get(&ip) {
+            return id;
+        }
+        let id = self.locations.len() as u64 + 1;
+        let mut location = Vec::new();
+        field_varint(&mut location, 1, id);
+        field_varint(&mut location, 3, ip as u64);
+        for frame in inlined {
+            let mut line = Vec::new();
+            field_varint(&mut line, 1, self.function(frame));
+            field_varint(&mut line, 2, frame.line as u64);
+            field_bytes(&mut location, 4, &line);
+        }
+        self.locations.push(location);
+        self.location_ids.insert(ip, id);
+        id
+    }
+}
+
+fn varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn field_varint(buf: &mut Vec<u8>, field: u32, v: u64) {
+    varint(buf, (field as u64) << 3);
+    varint(buf, v);
+}
+
+fn field_bytes(buf: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    varint(buf, ((field as u64) << 3) | 2);
+    varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn field_packed(buf: &mut Vec<u8>, field: u32, values: &[u64]) {
+    let mut packed = Vec::new();
+    for &v in values {
+        varint(&mut packed, v);
+    }
+    field_bytes(buf, field, &packed);
+}
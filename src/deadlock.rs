@@ -0,0 +1,398 @@
+//! Runtime lock-order checking, enabled by the `deadlock-detection` feature
+//! in debug builds.
+//!
+//! Every blocking acquisition of a `Mutex` or `RwLock` records an edge from
+//! each lock the thread already holds to the one it is acquiring. The first
+//! time a new edge closes a cycle in that graph, the cycle is reported, even
+//! if the threads involved never actually deadlocked.
+//!
+//! Release builds keep the public API but never check, so the handler set
+//! with [`set_lock_order_handler`] is never called.
+
+#[cfg(feature = "deadlock-detection")]
+use std::{backtrace::Backtrace, fmt, sync::Arc};
+#[cfg(all(feature = "deadlock-detection", debug_assertions))]
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A lazily assigned identity for a lock, stable for its whole life.
+///
+/// Addresses are not used because a freed lock's address can be reused by an
+/// unrelated lock, which would produce bogus edges. Dropping the id removes
+/// the lock from the graph.
+pub(crate) struct LockId {
+    #[cfg(all(feature = "deadlock-detection", debug_assertions))]
+    id: AtomicUsize,
+}
+
+impl LockId {
+    pub(crate) const fn new() -> LockId {
+        LockId {
+            #[cfg(all(feature = "deadlock-detection", debug_assertions))]
+            id: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the lock's id, assigning one on first use. `name` is the
+    /// lock's registry name, shown in reports.
+    #[cfg(all(feature = "deadlock-detection", debug_assertions))]
+    fn get(&self, name: Option<&str>) -> usize {
+        static NEXT: AtomicUsize = AtomicUsize::new(1);
+        let id = self.id.load(Ordering::Relaxed);
+        if id != 0 {
+            return id;
+        }
+        let new = NEXT.fetch_add(1, Ordering::Relaxed);
+        match self
+            .id
+            .compare_exchange(0, new, Ordering::Relaxed, Ordering::Relaxed)
+        {
+            Ok(_) => {
+                if let Some(name) = name {
+                    let mut graph = GRAPH.lock();
+                    let graph = graph.get_or_insert_with(Graph::default);
+                    graph.names.insert(new, name.to_string());
+                }
+                new
+            }
+            Err(existing) => existing,
+        }
+    }
+}
+
+#[cfg(all(feature = "deadlock-detection", debug_assertions))]
+impl Drop for LockId {
+    fn drop(&mut self) {
+        let id = *self.id.get_mut();
+        if id != 0
+            && let Some(graph) = GRAPH.lock().as_mut()
+        {
+            graph.remove(id);
+        }
+    }
+}
+
+/// Called before blocking on `lock`, whose registry name is `name`: records
+/// the new ordering edges and reports any cycle they close.
+#[inline]
+pub(crate) fn before_lock(lock: &LockId, name: Option<&str>) {
+    #[cfg(all(feature = "deadlock-detection", debug_assertions))]
+    check_order(lock.get(name));
+    #[cfg(not(all(feature = "deadlock-detection", debug_assertions)))]
+    let _ = (lock, name);
+}
+
+/// Called once `lock`, whose registry name is `name`, is held by the
+/// current thread.
+#[inline]
+pub(crate) fn acquired(lock: &LockId, name: Option<&str>) {
+    #[cfg(all(feature = "deadlock-detection", debug_assertions))]
+    {
+        let id = lock.get(name);
+        let _ = HELD.try_with(|held| held.borrow_mut().push(id));
+    }
+    #[cfg(not(all(feature = "deadlock-detection", debug_assertions)))]
+    let _ = (lock, name);
+}
+
+/// Called when the current thread releases `lock`.
+#[inline]
+pub(crate) fn released(lock: &LockId) {
+    #[cfg(all(feature = "deadlock-detection", debug_assertions))]
+    {
+        let id = lock.get(None);
+        // Locks are not always released in the reverse order of acquisition.
+        let _ = HELD.try_with(|held| {
+            let mut held = held.borrow_mut();
+            if let Some(pos) = held.iter().rposition(|&h| h == id) {
+                held.remove(pos);
+            }
+        });
+    }
+    #[cfg(not(all(feature = "deadlock-detection", debug_assertions)))]
+    let _ = lock;
+}
+
+#[cfg(all(feature = "deadlock-detection", debug_assertions))]
+thread_local! {
+    static HELD: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// One edge of a lock-order cycle: `to` was acquired while holding `from`.
+#[cfg(feature = "deadlock-detection")]
+#[derive(Debug, Clone)]
+pub struct LockOrderEdge {
+    /// The lock that was already held.
+    pub from: usize,
+    /// The name of `from`, if it was created with `named`.
+    pub from_name: Option<String>,
+    /// The lock that was then acquired.
+    pub to: usize,
+    /// The name of `to`, if it was created with `named`.
+    pub to_name: Option<String>,
+    /// Where `to` was first acquired while holding `from`.
+    pub backtrace: Arc<Backtrace>,
+}
+
+/// A cycle in the lock-order graph, i.e. a potential deadlock.
+///
+/// Locks are identified by opaque ids that stay stable for the life of the
+/// lock, and by name if they were created with `named`.
+#[cfg(feature = "deadlock-detection")]
+#[derive(Debug, Clone)]
+pub struct LockOrderViolation {
+    /// The edges making up the cycle; the first is the one just added.
+    pub edges: Vec<LockOrderEdge>,
+}
+
+#[cfg(feature = "deadlock-detection")]
+impl fmt::Display for LockOrderViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lock = |id: usize, name: &Option<String>| match name {
+            Some(name) => format!("lock `{name}`"),
+            None => format!("lock #{id}"),
+        };
+        writeln!(f, "lock order inversion detected:")?;
+        for edge in &self.edges {
+            writeln!(
+                f,
+                "{} acquired while holding {} at:\n{}",
+                lock(edge.to, &edge.to_name),
+                lock(edge.from, &edge.from_name),
+                edge.backtrace
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(feature = "deadlock-detection", debug_assertions))]
+type Handler = Box<dyn Fn(&LockOrderViolation) + Send + Sync>;
+
+#[cfg(all(feature = "deadlock-detection", debug_assertions))]
+static HANDLER: crate::nopoison::Mutex<Option<Arc<Handler>>> = crate::nopoison::Mutex::new(None);
+
+/// Sets the function called when a lock-order cycle is found.
+///
+/// By default the violation is printed to stderr. Release builds do not
+/// check lock order, so there this does nothing.
+#[cfg(feature = "deadlock-detection")]
+pub fn set_lock_order_handler<F>(handler: F)
+where
+    F: Fn(&LockOrderViolation) + Send + Sync + 'static,
+{
+    #[cfg(debug_assertions)]
+    {
+        *HANDLER.lock() = Some(Arc::new(Box::new(handler)));
+    }
+    #[cfg(not(debug_assertions))]
+    drop(handler);
+}
+
+/// The lock-order graph, over the locks that are still alive.
+#[cfg(all(feature = "deadlock-detection", debug_assertions))]
+#[derive(Default)]
+struct Graph {
+    /// `edges[from][to]` is where `to` was first acquired while holding
+    /// `from`.
+    edges: HashMap<usize, HashMap<usize, Arc<Backtrace>>>,
+    /// The names of named locks.
+    names: HashMap<usize, String>,
+}
+
+#[cfg(all(feature = "deadlock-detection", debug_assertions))]
+impl Graph {
+    /// Forgets the dropped lock `id` and every edge to or from it.
+    fn remove(&mut self, id: usize) {
+        self.edges.remove(&id);
+        self.edges.retain(|_, to| {
+            to.remove(&id);
+            !to.is_empty()
+        });
+        self.names.remove(&id);
+    }
+
+    fn edge(&self, from: usize, to: usize, backtrace: Arc<Backtrace>) -> LockOrderEdge {
+        LockOrderEdge {
+            from,
+            from_name: self.names.get(&from).cloned(),
+            to,
+            to_name: self.names.get(&to).cloned(),
+            backtrace,
+        }
+    }
+}
+
+#[cfg(all(feature = "deadlock-detection", debug_assertions))]
+static GRAPH: crate::nopoison::Mutex<Option<Graph>> = crate::nopoison::Mutex::new(None);
+
+#[cfg(all(feature = "deadlock-detection", debug_assertions))]
+fn check_order(to: usize) {
+    let held: Vec<usize> = HELD
+        .try_with(|held| held.borrow().clone())
+        .unwrap_or_default();
+    if held.is_empty() {
+        return;
+    }
+
+    let mut violations = Vec::new();
+    {
+        let mut graph = GRAPH.lock();
+        let graph = graph.get_or_insert_with(Graph::default);
+        for &from in &held {
+            if from == to || graph.edges.get(&from).is_some_and(|e| e.contains_key(&to)) {
+                continue;
+            }
+            let backtrace = Arc::new(Backtrace::force_capture());
+            graph
+                .edges
+                .entry(from)
+                .or_default()
+                .insert(to, Arc::clone(&backtrace));
+            if let Some(path) = find_path(&graph.edges, to, from) {
+                let mut edges = vec![graph.edge(from, to, backtrace)];
+                edges.extend(path.windows(2).map(|w| {
+                    let backtrace = Arc::clone(&graph.edges[&w[0]][&w[1]]);
+                    graph.edge(w[0], w[1], backtrace)
+                }));
+                violations.push(LockOrderViolation { edges });
+            }
+        }
+    }
+
+    // Report outside the graph lock: the handler may itself take locks.
+    for violation in violations {
+        let handler = HANDLER.lock().clone();
+        match handler {
+            Some(handler) => handler(&violation),
+            None => eprintln!("nsync-rs: {violation}"),
+        }
+    }
+}
+
+/// Returns a path of locks from `start` to `goal`, if one exists.
+#[cfg(all(feature = "deadlock-detection", debug_assertions))]
+fn find_path(
+    graph: &HashMap<usize, HashMap<usize, Arc<Backtrace>>>,
+    start: usize,
+    goal: usize,
+) -> Option<Vec<usize>> {
+    let mut visited = HashSet::new();
+    let mut stack = vec![vec![start]];
+    while let Some(path) = stack.pop() {
+        let node = *path.last().unwrap();
+        if node == goal {
+            return Some(path);
+        }
+        if !visited.insert(node) {
+            continue;
+        }
+        if let Some(next) = graph.get(&node) {
+            for &n in next.keys() {
+                let mut p = path.clone();
+                p.push(n);
+                stack.push(p);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(all(test, feature = "deadlock-detection", debug_assertions))]
+mod tests {
+    use super::*;
+    use crate::Mutex;
+    use std::sync::OnceLock;
+
+    static SEEN: crate::nopoison::Mutex<Vec<LockOrderViolation>> =
+        crate::nopoison::Mutex::new(Vec::new());
+
+    /// Installs a handler that keeps every violation. Tests share it, so
+    /// each uses its own lock names.
+    fn record_violations() {
+        static INSTALL: OnceLock<()> = OnceLock::new();
+        INSTALL.get_or_init(|| set_lock_order_handler(|v| SEEN.lock().push(v.clone())));
+    }
+
+    fn violations_naming(lock: &str) -> Vec<LockOrderViolation> {
+        let names = |e: &LockOrderEdge| [e.from_name.clone(), e.to_name.clone()];
+        SEEN.lock()
+            .iter()
+            .filter(|v| {
+                v.edges
+                    .iter()
+                    .flat_map(names)
+                    .any(|n| n.as_deref() == Some(lock))
+            })
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn reports_an_inversion_with_lock_names() {
+        record_violations();
+        let a = Mutex::named("inversion a", ());
+        let b = Mutex::named("inversion b", ());
+        {
+            let _a = a.lock().unwrap();
+            let _b = b.lock().unwrap();
+        }
+        assert!(violations_naming("inversion a").is_empty());
+        {
+            let _b = b.lock().unwrap();
+            let _a = a.lock().unwrap();
+        }
+        let violations = violations_naming("inversion a");
+        assert_eq!(violations.len(), 1);
+        let edges = &violations[0].edges;
+        assert_eq!(edges.len(), 2);
+        assert_eq!(edges[0].from_name.as_deref(), Some("inversion b"));
+        assert_eq!(edges[0].to_name.as_deref(), Some("inversion a"));
+        let report = violations[0].to_string();
+        assert!(report.contains("lock `inversion a` acquired while holding lock `inversion b`"));
+    }
+
+    #[test]
+    fn a_consistent_order_is_not_reported() {
+        record_violations();
+        let a = Mutex::named("consistent a", ());
+        let b = Mutex::named("consistent b", ());
+        for _ in 0..3 {
+            let _a = a.lock().unwrap();
+            let _b = b.lock().unwrap();
+        }
+        assert!(violations_naming("consistent a").is_empty());
+    }
+
+    #[test]
+    fn dropped_locks_leave_the_graph() {
+        let mut graph = Graph::default();
+        let backtrace = Arc::new(Backtrace::disabled());
+        graph
+            .edges
+            .entry(1)
+            .or_default()
+            .insert(2, backtrace.clone());
+        graph.edges.entry(2).or_default().insert(3, backtrace);
+        graph.names.insert(2, "middle".to_string());
+        assert_eq!(find_path(&graph.edges, 1, 3), Some(vec![1, 2, 3]));
+        graph.remove(2);
+        assert!(graph.edges.is_empty());
+        assert!(graph.names.is_empty());
+        assert_eq!(find_path(&graph.edges, 1, 3), None);
+    }
+
+    #[test]
+    fn finds_a_path_only_along_edges() {
+        let backtrace = Arc::new(Backtrace::disabled());
+        let mut edges: HashMap<usize, HashMap<usize, Arc<Backtrace>>> = HashMap::new();
+        for (from, to) in [(1, 2), (2, 3), (3, 1), (4, 1)] {
+            edges.entry(from).or_default().insert(to, backtrace.clone());
+        }
+        assert_eq!(find_path(&edges, 2, 1), Some(vec![2, 3, 1]));
+        assert_eq!(find_path(&edges, 1, 4), None);
+    }
+}
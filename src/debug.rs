@@ -0,0 +1,219 @@
+//! Parsed snapshots of nsync's debug state strings.
+
+use crate::ffi;
+use std::ffi::CStr;
+
+/// The size of the buffer handed to nsync's debug-state functions. Output
+/// longer than this is truncated by nsync.
+const DEBUG_BUF_LEN: usize = 4096;
+
+/// How a waiting thread wants to acquire a lock.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaiterKind {
+    /// The thread waits for shared (read) access.
+    Reader,
+    /// The thread waits for exclusive (write) access.
+    Writer,
+    /// nsync did not report the kind, e.g. for condition variable waiters.
+    Unknown,
+}
+
+/// One thread queued on a lock or condition variable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WaiterState {
+    /// How the thread wants to acquire the lock.
+    pub kind: WaiterKind,
+    /// Whether the thread is still blocked, rather than woken and not yet
+    /// dequeued.
+    pub waiting: bool,
+}
+
+/// A point-in-time view of a `Mutex` or `RwLock`.
+///
+/// The lock keeps changing while the snapshot is taken, so the fields need
+/// not be mutually consistent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockState {
+    /// Whether the lock is held in either mode.
+    pub held: bool,
+    /// Whether the lock is held for writing.
+    pub write_locked: bool,
+    /// The number of threads holding the lock for reading.
+    pub readers: u32,
+    /// Whether a writer is waiting for the lock.
+    pub writer_waiting: bool,
+    /// The threads queued on the lock.
+    pub waiters: Vec<WaiterState>,
+    /// nsync's unparsed description, from `nsync_mu_debug_state_and_waiters`.
+    pub raw: String,
+}
+
+/// A point-in-time view of a `Condvar`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CondvarState {
+    /// Whether any thread is waiting on the condition variable.
+    pub has_waiters: bool,
+    /// The threads queued on the condition variable.
+    pub waiters: Vec<WaiterState>,
+    /// nsync's unparsed description, from `nsync_cv_debug_state_and_waiters`.
+    pub raw: String,
+}
+
+pub(crate) fn mu_state(mu: *mut ffi::nsync_mu) -> LockState {
+    let mut buf = vec![0 as std::ffi::c_char; DEBUG_BUF_LEN];
+    let raw = unsafe {
+        let s = ffi::nsync_mu_debug_state_and_waiters(mu, buf.as_mut_ptr(), buf.len() as i32);
+        CStr::from_ptr(s).to_string_lossy().into_owned()
+    };
+    parse_mu_state(raw)
+}
+
+pub(crate) fn cv_state(cv: *mut ffi::nsync_cv) -> CondvarState {
+    let mut buf = vec![0 as std::ffi::c_char; DEBUG_BUF_LEN];
+    let raw = unsafe {
+        let s = ffi::nsync_cv_debug_state_and_waiters(cv, buf.as_mut_ptr(), buf.len() as i32);
+        CStr::from_ptr(s).to_string_lossy().into_owned()
+    };
+    parse_cv_state(raw)
+}
+
+/// Parses output such as
+/// `mu 0x7ff0 -> 0x3 = { wlock wait writer readers=0x2 }` followed by an
+/// optional `waiters =` section with one waiter per line.
+fn parse_mu_state(raw: String) -> LockState {
+    let flags = flags(&raw);
+    let readers = flags
+        .iter()
+        .find_map(|f| f.strip_prefix("readers="))
+        .and_then(parse_hex)
+        .unwrap_or(0);
+    let write_locked = flags.contains(&"wlock");
+    LockState {
+        held: write_locked || readers != 0,
+        write_locked,
+        readers,
+        writer_waiting: flags.contains(&"writer"),
+        waiters: waiters(&raw),
+        raw,
+    }
+}
+
+/// Parses output such as `cv 0x7ff0 -> 0x2 = { wait }` followed by an
+/// optional `waiters =` section.
+fn parse_cv_state(raw: String) -> CondvarState {
+    let waiters = waiters(&raw);
+    CondvarState {
+        has_waiters: flags(&raw).contains(&"wait") || !waiters.is_empty(),
+        waiters,
+        raw,
+    }
+}
+
+/// Returns the words between the braces of the state line.
+fn flags(raw: &str) -> Vec<&str> {
+    let line = raw.lines().next().unwrap_or("");
+    match (line.find('{'), line.rfind('}')) {
+        (Some(open), Some(close)) if open < close => {
+            line[open + 1..close].split_whitespace().collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn waiters(raw: &str) -> Vec<WaiterState> {
+    raw.lines()
+        .skip_while(|line| !line.trim_start().starts_with("waiters"))
+        .skip(1)
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let kind = if words.contains(&"writer") {
+                WaiterKind::Writer
+            } else if words.contains(&"reader") {
+                WaiterKind::Reader
+            } else {
+                WaiterKind::Unknown
+            };
+            let waiting = words
+                .iter()
+                .find_map(|w| w.strip_prefix("waiting="))
+                .and_then(parse_hex)
+                .is_none_or(|w| w != 0);
+            WaiterState { kind, waiting }
+        })
+        .collect()
+}
+
+/// nsync prints numbers in hexadecimal, with or without a `0x` prefix.
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s.trim_start_matches("0x"), 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_write_locked_mutex_with_waiters() {
+        let state = parse_mu_state(
+            "mu 0x7ff0 -> 0xa0 = { wlock wait writer readers=0x0 }\nwaiters =\n \
+             0x1000 writer waiting=1\n 0x2000 reader waiting=0\n"
+                .to_string(),
+        );
+        assert!(state.held);
+        assert!(state.write_locked);
+        assert_eq!(state.readers, 0);
+        assert!(state.writer_waiting);
+        assert_eq!(
+            state.waiters,
+            [
+                WaiterState {
+                    kind: WaiterKind::Writer,
+                    waiting: true,
+                },
+                WaiterState {
+                    kind: WaiterKind::Reader,
+                    waiting: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_a_read_locked_mutex() {
+        let state = parse_mu_state("mu 0x7ff0 -> 0x200 = { readers=0x2 }\n".to_string());
+        assert!(state.held);
+        assert!(!state.write_locked);
+        assert_eq!(state.readers, 2);
+        assert!(!state.writer_waiting);
+        assert!(state.waiters.is_empty());
+    }
+
+    #[test]
+    fn parses_an_idle_mutex_and_tolerates_garbage() {
+        let idle = parse_mu_state("mu 0x7ff0 -> 0x0 = { }\n".to_string());
+        assert!(!idle.held);
+        let garbage = parse_mu_state("truncated {".to_string());
+        assert!(!garbage.held);
+        assert!(garbage.waiters.is_empty());
+        assert_eq!(garbage.raw, "truncated {");
+    }
+
+    #[test]
+    fn parses_a_condvar() {
+        let waiting = parse_cv_state("cv 0x7ff0 -> 0x2 = { wait }\nwaiters =\n 0x1000\n".into());
+        assert!(waiting.has_waiters);
+        assert_eq!(waiting.waiters.len(), 1);
+        assert_eq!(waiting.waiters[0].kind, WaiterKind::Unknown);
+        assert!(waiting.waiters[0].waiting);
+        let idle = parse_cv_state("cv 0x7ff0 -> 0x0 = { }\n".into());
+        assert!(!idle.has_waiters);
+    }
+
+    #[test]
+    fn parses_hex_with_or_without_prefix() {
+        assert_eq!(parse_hex("0x1f"), Some(31));
+        assert_eq!(parse_hex("1f"), Some(31));
+        assert_eq!(parse_hex("0xz"), None);
+    }
+}
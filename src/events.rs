@@ -0,0 +1,338 @@
+//! An in-memory recorder of synchronization events, enabled by the
+//! `trace-events` feature, for reconstructing how threads interleaved around
+//! a latency spike.
+//!
+//! Recording is off until [`start`] is called. Events go into a fixed-size
+//! ring buffer, so a long-running process keeps only the most recent ones,
+//! and are exported as Chrome `trace_event` JSON, which Perfetto and
+//! `chrome://tracing` can open.
+
+#[cfg(feature = "trace-events")]
+use crate::nopoison;
+#[cfg(feature = "trace-events")]
+use std::{
+    cell::Cell,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    io::{self, Write},
+    sync::OnceLock,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Instant,
+};
+
+/// Something a thread did to a lock, condition variable, note or counter.
+#[derive(Clone, Copy)]
+#[cfg_attr(not(feature = "trace-events"), allow(dead_code))]
+pub(crate) enum Event {
+    LockRequested,
+    LockAcquired,
+    LockReleased,
+    CondvarWait,
+    CondvarWoken,
+    CondvarSignal,
+    CondvarBroadcast,
+    NoteNotify,
+    NoteWait,
+    NoteWoken { notified: bool },
+    CounterAdd { delta: i32, value: u32 },
+    CounterWait,
+    CounterWoken { value: u32 },
+}
+
+/// Records `event` on the object at address `object`, if recording is on.
+/// `name` describes the object and is only called while recording.
+#[inline]
+pub(crate) fn record(event: Event, object: usize, name: impl FnOnce() -> String) {
+    #[cfg(feature = "trace-events")]
+    if RECORDING.load(Ordering::Relaxed) {
+        push(event, object, name());
+    }
+    #[cfg(not(feature = "trace-events"))]
+    let _ = (event, object, name);
+}
+
+#[cfg(feature = "trace-events")]
+struct Record {
+    event: Event,
+    object: usize,
+    name: String,
+    thread: u64,
+    nanos: u64,
+}
+
+#[cfg(feature = "trace-events")]
+struct Ring {
+    capacity: usize,
+    records: VecDeque<Record>,
+    /// The name of, and number of records from, each thread with records in
+    /// the ring, by recorder thread id, for the trace's metadata events.
+    threads: BTreeMap<u64, (String, usize)>,
+}
+
+#[cfg(feature = "trace-events")]
+impl Ring {
+    /// Drops the oldest record, and its thread once none of its records
+    /// are left.
+    fn evict(&mut self) {
+        let Some(record) = self.records.pop_front() else {
+            return;
+        };
+        if let Some((_, count)) = self.threads.get_mut(&record.thread) {
+            *count -= 1;
+            if *count == 0 {
+                self.threads.remove(&record.thread);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "trace-events")]
+static RECORDING: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "trace-events")]
+static RING: nopoison::Mutex<Ring> = nopoison::Mutex::new(Ring {
+    capacity: 0,
+    records: VecDeque::new(),
+    threads: BTreeMap::new(),
+});
+
+/// The instant timestamps are measured from.
+#[cfg(feature = "trace-events")]
+fn epoch() -> Instant {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    *EPOCH.get_or_init(Instant::now)
+}
+
+#[cfg(feature = "trace-events")]
+fn push(event: Event, object: usize, name: String) {
+    static NEXT_THREAD: AtomicU64 = AtomicU64::new(1);
+    thread_local! {
+        static THREAD: Cell<u64> = const { Cell::new(0) };
+    }
+    let nanos = epoch().elapsed().as_nanos() as u64;
+    let thread = THREAD
+        .try_with(|t| {
+            if t.get() == 0 {
+                t.set(NEXT_THREAD.fetch_add(1, Ordering::Relaxed));
+            }
+            t.get()
+        })
+        .unwrap_or(0);
+
+    let mut ring = RING.lock();
+    if ring.capacity == 0 {
+        return;
+    }
+    if ring.records.len() == ring.capacity {
+        ring.evict();
+    }
+    let (_, count) = ring.threads.entry(thread).or_insert_with(|| {
+        let current = std::thread::current();
+        let name = match current.name() {
+            Some(n) => n.to_string(),
+            None => format!("{:?}", current.id()),
+        };
+        (name, 0)
+    });
+    *count += 1;
+    ring.records.push_back(Record {
+        event,
+        object,
+        name,
+        thread,
+        nanos,
+    });
+}
+
+/// Starts recording, keeping the most recent `capacity` events.
+///
+/// Calling `start` while already recording changes the capacity and keeps
+/// the events that still fit.
+#[cfg(feature = "trace-events")]
+pub fn start(capacity: usize) {
+    epoch();
+    let mut ring = RING.lock();
+    ring.capacity = capacity;
+    while ring.records.len() > capacity {
+        ring.evict();
+    }
+    RECORDING.store(capacity > 0, Ordering::Relaxed);
+}
+
+/// Stops recording. The events recorded so far are kept for export.
+#[cfg(feature = "trace-events")]
+pub fn stop() {
+    RECORDING.store(false, Ordering::Relaxed);
+}
+
+/// Discards all recorded events.
+#[cfg(feature = "trace-events")]
+pub fn clear() {
+    let mut ring = RING.lock();
+    ring.records.clear();
+    ring.threads.clear();
+}
+
+/// Writes the recorded events as Chrome `trace_event` JSON.
+///
+/// Time spent blocked acquiring a lock or waiting on a condition variable,
+/// note or counter appears as a slice on the waiting thread's track. Holds
+/// appear as async slices named after the lock, and notifications, signals
+/// and counter updates as instant events. Events whose partner fell out of
+/// the ring buffer are written as instant events.
+#[cfg(feature = "trace-events")]
+pub fn write_chrome_trace(out: &mut dyn Write) -> io::Result<()> {
+    let (records, threads): (Vec<Record>, Vec<(u64, String)>) = {
+        let ring = RING.lock();
+        let records = ring
+            .records
+            .iter()
+            .map(|r| Record {
+                event: r.event,
+                object: r.object,
+                name: r.name.clone(),
+                thread: r.thread,
+                nanos: r.nanos,
+            })
+            .collect();
+        let threads = ring
+            .threads
+            .iter()
+            .map(|(&thread, (name, _))| (thread, name.clone()))
+            .collect();
+        (records, threads)
+    };
+
+    let mut events = Vec::new();
+    for (thread, name) in threads {
+        events.push(format!(
+            r#"{{"name":"thread_name","ph":"M","pid":1,"tid":{thread},"args":{{"name":{}}}}}"#,
+            json_string(&name)
+        ));
+    }
+
+    // The start of each thread's pending wait, keyed by thread and object.
+    let mut waiting: HashMap<(u64, usize), &Record> = HashMap::new();
+    let mut holding = HashSet::new();
+    for r in &records {
+        let key = (r.thread, r.object);
+        match r.event {
+            Event::LockRequested | Event::CondvarWait | Event::NoteWait | Event::CounterWait => {
+                waiting.insert(key, r);
+            }
+            Event::LockAcquired => {
+                if let Some(start) = waiting.remove(&key) {
+                    events.push(slice(start, r, "lock", &format!("wait {}", r.name), "{}"));
+                }
+                holding.insert(key);
+                events.push(hold(r, "b"));
+            }
+            Event::LockReleased => {
+                if holding.remove(&key) {
+                    events.push(hold(r, "e"));
+                } else {
+                    events.push(instant(r, "lock", "release", "{}"));
+                }
+            }
+            Event::CondvarWoken => {
+                let name = format!("wait {}", r.name);
+                match waiting.remove(&key) {
+                    Some(start) => events.push(slice(start, r, "condvar", &name, "{}")),
+                    None => events.push(instant(r, "condvar", "woken", "{}")),
+                }
+            }
+            Event::NoteWoken { notified } => {
+                let args = format!(r#"{{"notified":{notified}}}"#);
+                let name = format!("wait {}", r.name);
+                match waiting.remove(&key) {
+                    Some(start) => events.push(slice(start, r, "note", &name, &args)),
+                    None => events.push(instant(r, "note", "woken", &args)),
+                }
+            }
+            Event::CounterWoken { value } => {
+                let args = format!(r#"{{"value":{value}}}"#);
+                let name = format!("wait {}", r.name);
+                match waiting.remove(&key) {
+                    Some(start) => events.push(slice(start, r, "counter", &name, &args)),
+                    None => events.push(instant(r, "counter", "woken", &args)),
+                }
+            }
+            Event::CondvarSignal => events.push(instant(r, "condvar", "signal", "{}")),
+            Event::CondvarBroadcast => events.push(instant(r, "condvar", "broadcast", "{}")),
+            Event::NoteNotify => events.push(instant(r, "note", "notify", "{}")),
+            Event::CounterAdd { delta, value } => {
+                let args = format!(r#"{{"delta":{delta},"value":{value}}}"#);
+                events.push(instant(r, "counter", "add", &args));
+            }
+        }
+    }
+    // Waits still pending when recording stopped.
+    let mut pending: Vec<&Record> = waiting.into_values().collect();
+    pending.sort_by_key(|r| r.nanos);
+    for r in pending {
+        events.push(instant(r, "wait", "waiting", "{}"));
+    }
+
+    out.write_all(b"{\"traceEvents\":[\n")?;
+    for (i, event) in events.iter().enumerate() {
+        let separator = if i + 1 < events.len() { ",\n" } else { "\n" };
+        write!(out, "{event}{separator}")?;
+    }
+    out.write_all(b"],\"displayTimeUnit\":\"ns\"}\n")
+}
+
+/// Formats nanoseconds as the microseconds Chrome traces use.
+#[cfg(feature = "trace-events")]
+fn micros(nanos: u64) -> String {
+    format!("{}.{:03}", nanos / 1000, nanos % 1000)
+}
+
+#[cfg(feature = "trace-events")]
+fn slice(start: &Record, end: &Record, cat: &str, name: &str, args: &str) -> String {
+    format!(
+        r#"{{"name":{},"cat":"{cat}","ph":"X","pid":1,"tid":{},"ts":{},"dur":{},"args":{args}}}"#,
+        json_string(name),
+        end.thread,
+        micros(start.nanos),
+        micros(end.nanos.saturating_sub(start.nanos)),
+    )
+}
+
+#[cfg(feature = "trace-events")]
+fn instant(r: &Record, cat: &str, what: &str, args: &str) -> String {
+    format!(
+        r#"{{"name":{},"cat":"{cat}","ph":"i","s":"t","pid":1,"tid":{},"ts":{},"args":{args}}}"#,
+        json_string(&format!("{what} {}", r.name)),
+        r.thread,
+        micros(r.nanos),
+    )
+}
+
+/// An async begin or end event for a hold, so that locks released out of
+/// acquisition order still produce well-formed slices.
+#[cfg(feature = "trace-events")]
+fn hold(r: &Record, phase: &str) -> String {
+    format!(
+        r#"{{"name":{},"cat":"hold","ph":"{phase}","id":"{:#x}.{}","pid":1,"tid":{},"ts":{}}}"#,
+        json_string(&format!("hold {}", r.name)),
+        r.object,
+        r.thread,
+        r.thread,
+        micros(r.nanos),
+    )
+}
+
+#[cfg(feature = "trace-events")]
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
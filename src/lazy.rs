@@ -0,0 +1,329 @@
+use crate::once::Once;
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::mem::MaybeUninit;
+use std::ops::Deref;
+
+/// A cell which can be written to only once, built on [`Once`].
+///
+/// If an initialization closure panics, the cell stays empty and the next
+/// call to [`get_or_init`](OnceLock::get_or_init) tries again.
+pub struct OnceLock<T> {
+    once: Once,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send> Send for OnceLock<T> {}
+unsafe impl<T: Send + Sync> Sync for OnceLock<T> {}
+
+impl<T> OnceLock<T> {
+    /// Creates a new empty cell.
+    pub const fn new() -> OnceLock<T> {
+        OnceLock {
+            once: Once::new(),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Returns the value, or `None` if the cell is empty.
+    pub fn get(&self) -> Option<&T> {
+        if self.once.is_completed() {
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    /// Returns a mutable reference to the value, or `None` if the cell is
+    /// empty.
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        if self.once.is_completed() {
+            Some(unsafe { self.value.get_mut().assume_init_mut() })
+        } else {
+            None
+        }
+    }
+
+    /// Stores `value` if the cell is empty, otherwise returns it in `Err`.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        self.get_or_init(|| value.take().unwrap());
+        match value {
+            None => Ok(()),
+            Some(value) => Err(value),
+        }
+    }
+
+    /// Returns the value, initializing it with `f` if the cell is empty.
+    ///
+    /// Concurrent callers block until one of them has initialized the cell.
+    /// If `f` panics, the panic propagates and the cell stays empty.
+    pub fn get_or_init<F>(&self, f: F) -> &T
+    where
+        F: FnOnce() -> T,
+    {
+        match self.get_or_try_init(|| Ok::<T, std::convert::Infallible>(f())) {
+            Ok(value) => value,
+            Err(never) => match never {},
+        }
+    }
+
+    /// Returns the value, initializing it with `f` if the cell is empty.
+    ///
+    /// If `f` returns an error, the error is returned and the cell stays
+    /// empty, so a later call can try again.
+    pub fn get_or_try_init<F, E>(&self, f: F) -> Result<&T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        if let Some(value) = self.get() {
+            return Ok(value);
+        }
+        let mut result = Ok(());
+        self.once.call_once_slow(true, |_| match f() {
+            Ok(value) => {
+                unsafe { (*self.value.get()).write(value) };
+                true
+            }
+            Err(e) => {
+                result = Err(e);
+                false
+            }
+        });
+        result?;
+        Ok(unsafe { (*self.value.get()).assume_init_ref() })
+    }
+
+    /// Consumes the cell, returning its value, or `None` if it is empty.
+    pub fn into_inner(mut self) -> Option<T> {
+        self.take()
+    }
+
+    /// Takes the value out, leaving the cell empty.
+    pub fn take(&mut self) -> Option<T> {
+        if self.once.is_completed() {
+            self.once = Once::new();
+            Some(unsafe { self.value.get_mut().assume_init_read() })
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Default for OnceLock<T> {
+    fn default() -> OnceLock<T> {
+        OnceLock::new()
+    }
+}
+
+impl<T> From<T> for OnceLock<T> {
+    fn from(value: T) -> OnceLock<T> {
+        let cell = OnceLock::new();
+        let _ = cell.set(value);
+        cell
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for OnceLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.get() {
+            Some(value) => f.debug_tuple("OnceLock").field(value).finish(),
+            None => f.write_str("OnceLock(<uninit>)"),
+        }
+    }
+}
+
+impl<T> Drop for OnceLock<T> {
+    fn drop(&mut self) {
+        if self.once.is_completed() {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+/// The name the `once_cell` crate gives [`OnceLock`], for code migrating
+/// from `once_cell::sync::OnceCell`.
+pub type OnceCell<T> = OnceLock<T>;
+
+/// A value which is initialized on first access, built on [`OnceLock`].
+///
+/// If the initialization function panics, the `LazyLock` is poisoned and
+/// every later access panics.
+pub struct LazyLock<T, F = fn() -> T> {
+    cell: OnceLock<T>,
+    init: UnsafeCell<Option<F>>,
+}
+
+unsafe impl<T: Send + Sync, F: Send> Sync for LazyLock<T, F> {}
+
+impl<T, F: FnOnce() -> T> LazyLock<T, F> {
+    /// Creates a new lazy value with the given initializing function.
+    pub const fn new(f: F) -> LazyLock<T, F> {
+        LazyLock {
+            cell: OnceLock::new(),
+            init: UnsafeCell::new(Some(f)),
+        }
+    }
+
+    /// Forces evaluation of this lazy value and returns a reference to it.
+    pub fn force(this: &LazyLock<T, F>) -> &T {
+        this.cell.get_or_init(|| {
+            // Only the thread initializing `cell` gets here.
+            match unsafe { (*this.init.get()).take() } {
+                Some(f) => f(),
+                None => panic!("LazyLock instance has previously been poisoned"),
+            }
+        })
+    }
+
+    /// Consumes this `LazyLock`, returning the value if it was initialized,
+    /// or the initializing function if not.
+    pub fn into_inner(this: LazyLock<T, F>) -> Result<T, F> {
+        let LazyLock { cell, init } = this;
+        match cell.into_inner() {
+            Some(value) => Ok(value),
+            None => Err(init
+                .into_inner()
+                .expect("LazyLock instance has previously been poisoned")),
+        }
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for LazyLock<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        LazyLock::force(self)
+    }
+}
+
+impl<T: Default> Default for LazyLock<T> {
+    fn default() -> LazyLock<T> {
+        LazyLock::new(T::default)
+    }
+}
+
+impl<T: fmt::Debug, F> fmt::Debug for LazyLock<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.cell.get() {
+            Some(value) => f.debug_tuple("LazyLock").field(value).finish(),
+            None => f.write_str("LazyLock(<uninit>)"),
+        }
+    }
+}
+
+/// Declares `static` values that are initialized on first access, in the
+/// style of the `lazy_static` crate.
+///
+/// Each `static ref NAME: T = expr;` becomes a
+/// `static NAME: LazyLock<T>` that dereferences to `T`.
+///
+/// ```
+/// nsync_rs::lazy_static! {
+///     static ref CONFIG: String = std::env::var("CONFIG").unwrap_or_default();
+///     pub static ref TABLE: Vec<u32> = (0..256).collect();
+/// }
+///
+/// println!("{} {}", *CONFIG, TABLE[7]);
+/// ```
+#[macro_export]
+macro_rules! lazy_static {
+    ($(#[$attr:meta])* $vis:vis static ref $name:ident : $t:ty = $init:expr; $($rest:tt)*) => {
+        $(#[$attr])*
+        $vis static $name: $crate::LazyLock<$t> = $crate::LazyLock::new(|| $init);
+        $crate::lazy_static!($($rest)*);
+    };
+    () => {};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    #[test]
+    fn set_get_and_take() {
+        let mut cell = OnceLock::new();
+        assert_eq!(cell.get(), None);
+        assert_eq!(cell.set(1), Ok(()));
+        assert_eq!(cell.set(2), Err(2));
+        assert_eq!(cell.get(), Some(&1));
+        *cell.get_mut().unwrap() += 1;
+        assert_eq!(cell.take(), Some(2));
+        assert_eq!(cell.get(), None);
+        assert_eq!(cell.get_or_init(|| 3), &3);
+        assert_eq!(cell.into_inner(), Some(3));
+    }
+
+    #[test]
+    fn initializes_once_across_threads() {
+        let cell = OnceLock::new();
+        let runs = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for i in 0..8 {
+                let (cell, runs) = (&cell, &runs);
+                s.spawn(move || {
+                    cell.get_or_init(|| {
+                        runs.fetch_add(1, Ordering::Relaxed);
+                        i
+                    });
+                });
+            }
+        });
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+        assert!(cell.get().is_some());
+    }
+
+    #[test]
+    fn failed_initialization_leaves_the_cell_empty() {
+        let cell = OnceLock::new();
+        assert_eq!(cell.get_or_try_init(|| Err("no")), Err("no"));
+        assert_eq!(cell.get(), None);
+        let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            cell.get_or_init(|| panic!("no"));
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(cell.get(), None);
+        assert_eq!(cell.get_or_try_init(|| Ok::<_, ()>(5)), Ok(&5));
+    }
+
+    #[test]
+    fn drops_the_value() {
+        struct Counted<'a>(&'a AtomicUsize);
+        impl Drop for Counted<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let drops = AtomicUsize::new(0);
+        drop(OnceLock::<Counted>::new());
+        drop(OnceLock::from(Counted(&drops)));
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn lazy_lock_initializes_on_first_access() {
+        let runs = AtomicUsize::new(0);
+        let lazy = LazyLock::new(|| {
+            runs.fetch_add(1, Ordering::Relaxed);
+            7
+        });
+        assert_eq!(runs.load(Ordering::Relaxed), 0);
+        assert_eq!(*lazy, 7);
+        assert_eq!(*lazy, 7);
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+        assert_eq!(LazyLock::into_inner(lazy).ok(), Some(7));
+    }
+
+    #[test]
+    fn a_panicking_lazy_lock_stays_poisoned() {
+        let lazy: LazyLock<u32> = LazyLock::new(|| panic!("init failed"));
+        let first = panic::catch_unwind(AssertUnwindSafe(|| *lazy));
+        assert!(first.is_err());
+        let second = panic::catch_unwind(AssertUnwindSafe(|| *lazy)).unwrap_err();
+        let message = second.downcast_ref::<&str>().copied().unwrap_or_default();
+        assert!(message.contains("previously been poisoned"), "{message}");
+    }
+}
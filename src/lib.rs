@@ -1,23 +1,106 @@
+mod barrier;
+mod callback;
+mod checked;
 mod condvar;
+#[cfg(feature = "contention-profile")]
+mod contention;
+mod deadlock;
+mod debug;
+mod events;
+mod lazy;
+mod lock_all;
 mod mutex;
+pub mod nopoison;
 mod note;
 mod once;
+mod openmetrics;
+mod poison;
+mod profiling;
+mod rank;
+#[cfg(feature = "lock_api")]
+mod raw;
+mod reentrant;
+mod registry;
+mod semaphore;
+mod span;
 mod time;
+mod track;
+mod wait_group;
+mod watchdog;
 /// # nsync-rs
 /// A safe Rust wrapper around Google's nsync synchronization library.
 /// This crate provides safe abstractions over nsync's synchronization primitives including:
 ///
 /// Mutexes (with reader-writer support)
 /// Condition variables
-/// Once initialization
+/// Once initialization, OnceLock, OnceCell and LazyLock
 /// Notes (cancellable waits)
-/// Counters
+/// Counters and wait groups
+/// Barriers, cyclic barriers and phasers
+/// Semaphores
 /// Time utilities
-pub use condvar::Condvar;
-pub use mutex::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
-pub use note::{Counter, Note};
-pub use once::Once;
+pub use barrier::{Barrier, BarrierWaitResult, CyclicBarrier, Phaser};
+pub use condvar::{Condvar, WaitTimeoutResult};
+#[cfg(feature = "deadlock-detection")]
+pub use deadlock::{LockOrderEdge, LockOrderViolation, set_lock_order_handler};
+pub use debug::{CondvarState, LockState, WaiterKind, WaiterState};
+pub use lazy::{LazyLock, OnceCell, OnceLock};
+pub use lock_all::{LockAll, Lockable, lock_all, try_lock_all};
+pub use mutex::{
+    LockResult, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    TryLockError, TryLockResult,
+};
+pub use note::{Counter, CounterError, Note, WaitOutcome};
+pub use once::{Once, OnceState};
+pub use poison::PoisonInfo;
+#[cfg(feature = "poison-info")]
+pub use poison::install_panic_hook;
+pub use rank::LockRank;
+#[cfg(feature = "lock_api")]
+pub use raw::{RawMutex, RawRwLock};
+pub use reentrant::{ReentrantMutex, ReentrantMutexGuard};
+pub use registry::dump_all_locks;
+#[cfg(all(feature = "sigquit", unix))]
+pub use registry::dump_on_sigquit;
+pub use semaphore::{Semaphore, SemaphorePermit};
+#[cfg(feature = "tracing")]
+pub use span::set_slow_wait_threshold;
 pub use time::{Duration, Time};
+pub use wait_group::{WaitGroup, WaitGroupToken};
+pub use watchdog::{Stall, StallKind, Watchdog};
+
+/// Lock contention profiling, enabled by the `profiling` feature.
+///
+/// Every `Mutex`, `RwLock` and `Condvar` records how often it was contended
+/// and how long threads waited for and held it. Use [`profile::report`] to
+/// decide which locks to shard.
+///
+/// The `contention-profile` feature adds sampled call stacks of contended
+/// acquisitions, exported as folded stacks or pprof profiles.
+#[cfg(feature = "profiling")]
+pub mod profile {
+    #[cfg(feature = "contention-profile")]
+    pub use crate::contention::{set_contention_sample_rate, write_folded, write_pprof};
+    pub use crate::profiling::{LockProfile, Summary, report, reset};
+}
+
+/// Prometheus metrics for named locks, condition variables and counters,
+/// enabled by the `metrics` feature.
+#[cfg(feature = "metrics")]
+pub mod metrics {
+    pub use crate::openmetrics::render_openmetrics;
+}
+
+/// Recording of lock, condition variable, note and counter events, enabled
+/// by the `trace-events` feature.
+///
+/// Call [`trace::start`] to record into a ring buffer, then
+/// [`trace::write_chrome_trace`] to export Chrome `trace_event` JSON for
+/// Perfetto or `chrome://tracing`.
+#[cfg(feature = "trace-events")]
+pub mod trace {
+    pub use crate::events::{clear, start, stop, write_chrome_trace};
+}
 
 #[doc(hidden)]
 pub mod ffi {
@@ -0,0 +1,336 @@
+use crate::ffi;
+use crate::mutex::{
+    LockResult, Mutex, MutexGuard, PoisonError, RwLock, RwLockWriteGuard, TryLockError,
+    TryLockResult,
+};
+use crate::track::Tracker;
+
+// `Sealed` cannot be named outside the crate, so the crate-private types in
+// its methods do not leak.
+#[allow(private_interfaces)]
+mod sealed {
+    use super::*;
+
+    /// The crate-internal half of [`Lockable`](super::Lockable), which also
+    /// keeps other crates from implementing it with a bogus mutex.
+    pub trait Sealed {
+        /// The lock's nsync mutex, which stays valid while `self` is alive.
+        fn raw_mu(&self) -> *mut ffi::nsync_mu;
+
+        fn track(&self) -> &Tracker;
+
+        /// Returns the lock's poison error, if it is poisoned.
+        fn poison(&self) -> Option<PoisonError<()>>;
+    }
+
+    impl<T: ?Sized> Sealed for &Mutex<T> {
+        fn raw_mu(&self) -> *mut ffi::nsync_mu {
+            self._inner.get()
+        }
+
+        fn track(&self) -> &Tracker {
+            &self.track
+        }
+
+        fn poison(&self) -> Option<PoisonError<()>> {
+            self.poison.get().then(|| self.poison.error(()))
+        }
+    }
+
+    impl<T: ?Sized> Sealed for &RwLock<T> {
+        fn raw_mu(&self) -> *mut ffi::nsync_mu {
+            self.inner.get()
+        }
+
+        fn track(&self) -> &Tracker {
+            &self.track
+        }
+
+        fn poison(&self) -> Option<PoisonError<()>> {
+            self.poison.get().then(|| self.poison.error(()))
+        }
+    }
+}
+
+/// A lock that can take part in [`lock_all`] and [`try_lock_all`].
+///
+/// Implemented for `&Mutex<T>` and `&RwLock<T>`; the latter is locked for
+/// writing. This trait is sealed: it cannot be implemented outside this
+/// crate.
+pub trait Lockable<'a>: sealed::Sealed + Sized {
+    /// The guard returned once the lock is held.
+    type Guard: 'a;
+
+    /// # Safety
+    ///
+    /// The current thread must hold the lock in write mode.
+    #[doc(hidden)]
+    unsafe fn make_guard(self) -> Self::Guard;
+}
+
+impl<'a, T: ?Sized> Lockable<'a> for &'a Mutex<T> {
+    type Guard = MutexGuard<'a, T>;
+
+    unsafe fn make_guard(self) -> MutexGuard<'a, T> {
+        self.track.acquired();
+        MutexGuard::new(self).unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<'a, T: ?Sized> Lockable<'a> for &'a RwLock<T> {
+    type Guard = RwLockWriteGuard<'a, T>;
+
+    unsafe fn make_guard(self) -> RwLockWriteGuard<'a, T> {
+        self.track.acquired();
+        RwLockWriteGuard::new(self).unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A tuple of [`Lockable`]s that can be acquired together.
+pub trait LockAll<'a> {
+    /// The tuple of guards returned once every lock is held.
+    type Guards;
+
+    #[doc(hidden)]
+    fn lock_all(self) -> LockResult<Self::Guards>;
+
+    #[doc(hidden)]
+    fn try_lock_all(self) -> TryLockResult<Self::Guards>;
+}
+
+/// Acquires every lock in `locks`, blocking until all of them are held.
+///
+/// The locks are taken in address order. Only the first is waited on; the
+/// rest are tried, and if one is busy everything is released and the busy
+/// lock is waited on first next time. A batch therefore never deadlocks,
+/// whatever order other threads use.
+///
+/// If any of the locks is poisoned, all guards are returned inside the
+/// `PoisonError` of the first poisoned lock in the tuple.
+///
+/// # Panics
+///
+/// Panics if the same lock appears twice.
+///
+/// # Examples
+///
+/// ```
+/// use nsync_rs::Mutex;
+///
+/// let a = Mutex::new(100);
+/// let b = Mutex::new(0);
+///
+/// let (mut from, mut to) = nsync_rs::lock_all((&a, &b)).unwrap();
+/// *from -= 10;
+/// *to += 10;
+/// ```
+pub fn lock_all<'a, L: LockAll<'a>>(locks: L) -> LockResult<L::Guards> {
+    locks.lock_all()
+}
+
+/// Attempts to acquire every lock in `locks` without blocking.
+///
+/// Either all locks are acquired or none are.
+///
+/// # Panics
+///
+/// Panics if the same lock appears twice.
+pub fn try_lock_all<'a, L: LockAll<'a>>(locks: L) -> TryLockResult<L::Guards> {
+    locks.try_lock_all()
+}
+
+/// A lock in a batch: its nsync mutex and its tracker.
+type Entry<'t> = (*mut ffi::nsync_mu, &'t Tracker);
+
+fn sort_checked(locks: &mut [Entry<'_>]) {
+    locks.sort_by_key(|&(mu, _)| mu as usize);
+    if locks.windows(2).any(|w| w[0].0 == w[1].0) {
+        panic!("lock_all: the same lock was passed more than once");
+    }
+}
+
+/// Tries to lock every mutex but `skip`, in order. On failure, releases those
+/// it got and returns the index of the busy one.
+unsafe fn try_rest(locks: &[Entry<'_>], skip: Option<usize>) -> Result<(), usize> {
+    for (i, &(mu, _)) in locks.iter().enumerate() {
+        if Some(i) == skip {
+            continue;
+        }
+        if unsafe { ffi::nsync_mu_trylock(mu) } == 0 {
+            for (j, &(held, _)) in locks[..i].iter().enumerate() {
+                if Some(j) != skip {
+                    unsafe { ffi::nsync_mu_unlock(held) };
+                }
+            }
+            return Err(i);
+        }
+    }
+    Ok(())
+}
+
+/// Locks every mutex in `locks`. The trackers see one blocking acquisition
+/// of each lock; the caller reports them all acquired once it has its
+/// guards.
+fn acquire(locks: &mut [Entry<'_>]) {
+    sort_checked(locks);
+    // No lock in the batch is held yet, so the checks only order the batch
+    // after the locks the thread already holds, not its members among
+    // themselves.
+    for &(_, track) in locks.iter() {
+        track.before_lock();
+    }
+    let mut first = 0;
+    loop {
+        let (mu, track) = locks[first];
+        track.acquire(
+            mu,
+            || unsafe { ffi::nsync_mu_trylock(mu) != 0 },
+            || unsafe { ffi::nsync_mu_lock(mu) },
+        );
+        match unsafe { try_rest(locks, Some(first)) } {
+            Ok(()) => break,
+            Err(busy) => {
+                unsafe { ffi::nsync_mu_unlock(mu) };
+                first = busy;
+            }
+        }
+        std::thread::yield_now();
+    }
+    for (i, &(_, track)) in locks.iter().enumerate() {
+        if i != first {
+            track.acquired_without_blocking();
+        }
+    }
+}
+
+fn try_acquire(locks: &mut [Entry<'_>]) -> bool {
+    sort_checked(locks);
+    unsafe { try_rest(locks, None).is_ok() }
+}
+
+macro_rules! lock_all_tuple {
+    ($($name:ident $idx:tt),+) => {
+        impl<'a, $($name: Lockable<'a>),+> LockAll<'a> for ($($name,)+) {
+            type Guards = ($($name::Guard,)+);
+
+            fn lock_all(self) -> LockResult<Self::Guards> {
+                acquire(&mut [$((self.$idx.raw_mu(), self.$idx.track())),+]);
+                let poison = None $(.or_else(|| self.$idx.poison()))+;
+                let guards = unsafe { ($(self.$idx.make_guard(),)+) };
+                match poison {
+                    Some(err) => Err(err.map(|()| guards)),
+                    None => Ok(guards),
+                }
+            }
+
+            fn try_lock_all(self) -> TryLockResult<Self::Guards> {
+                if !try_acquire(&mut [$((self.$idx.raw_mu(), self.$idx.track())),+]) {
+                    return Err(TryLockError::WouldBlock);
+                }
+                let poison = None $(.or_else(|| self.$idx.poison()))+;
+                let guards = unsafe { ($(self.$idx.make_guard(),)+) };
+                match poison {
+                    Some(err) => Err(TryLockError::Poisoned(err.map(|()| guards))),
+                    None => Ok(guards),
+                }
+            }
+        }
+    };
+}
+
+lock_all_tuple!(A 0, B 1);
+lock_all_tuple!(A 0, B 1, C 2);
+lock_all_tuple!(A 0, B 1, C 2, D 3);
+lock_all_tuple!(A 0, B 1, C 2, D 3, E 4);
+lock_all_tuple!(A 0, B 1, C 2, D 3, E 4, F 5);
+lock_all_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6);
+lock_all_tuple!(A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn locks_every_lock_and_releases_them_with_the_guards() {
+        let a = Mutex::new(1);
+        let b = RwLock::new(2);
+        let (mut x, mut y) = lock_all((&a, &b)).unwrap();
+        assert!(a.try_lock().is_err());
+        assert!(b.try_read().is_err());
+        *x += 10;
+        *y += 10;
+        drop((x, y));
+        assert_eq!(*a.lock().unwrap(), 11);
+        assert_eq!(*b.read().unwrap(), 12);
+    }
+
+    #[test]
+    fn try_lock_all_takes_all_or_none() {
+        let a = Mutex::new(());
+        let b = Mutex::new(());
+        let c = Mutex::new(());
+        let held = b.lock().unwrap();
+        assert!(matches!(
+            try_lock_all((&a, &b, &c)),
+            Err(TryLockError::WouldBlock)
+        ));
+        assert!(a.try_lock().is_ok());
+        assert!(c.try_lock().is_ok());
+        drop(held);
+        assert!(try_lock_all((&a, &b, &c)).is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "more than once")]
+    fn rejects_the_same_lock_twice() {
+        let a = Mutex::new(());
+        let _ = lock_all((&a, &a));
+    }
+
+    #[test]
+    fn returns_all_guards_when_a_lock_is_poisoned() {
+        let a = Arc::new(Mutex::new(1));
+        let b = Mutex::new(2);
+        let poisoner = Arc::clone(&a);
+        let _ = thread::spawn(move || {
+            let _guard = poisoner.lock().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+        let Err(err) = lock_all((&b, &*a)) else {
+            panic!("a poisoned lock was locked without an error");
+        };
+        let (x, y) = err.into_inner();
+        assert_eq!((*x, *y), (2, 1));
+    }
+
+    #[test]
+    fn opposite_orders_do_not_deadlock() {
+        let a = Arc::new(Mutex::new(0));
+        let b = Arc::new(Mutex::new(0));
+        let threads: Vec<_> = (0..2)
+            .map(|i| {
+                let (a, b) = (Arc::clone(&a), Arc::clone(&b));
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let (mut x, mut y) = if i == 0 {
+                            lock_all((&*a, &*b)).unwrap()
+                        } else {
+                            let (y, x) = lock_all((&*b, &*a)).unwrap();
+                            (x, y)
+                        };
+                        *x += 1;
+                        *y += 1;
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*a.lock().unwrap(), 2000);
+        assert_eq!(*b.lock().unwrap(), 2000);
+    }
+}
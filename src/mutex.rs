@@ -1,25 +1,97 @@
 use std::cell::UnsafeCell;
 use std::fmt::{self};
 use std::marker::PhantomData;
-use std::mem::MaybeUninit;
 use std::ops::{Deref, DerefMut};
 use std::panic::{RefUnwindSafe, UnwindSafe};
-use std::sync::atomic::Ordering;
 
+use crate::callback::Callback;
+use crate::debug::{self, LockState};
 use crate::ffi;
+use crate::note::{Note, WaitOutcome};
+use crate::poison::{Flag, PoisonInfo};
+use crate::profiling::HoldStart;
+use crate::rank::LockRank;
+use crate::registry::Kind;
+use crate::time::Time;
+use crate::track::Outcome;
+use crate::track::Tracker;
+
+/// A zeroed `nsync_mu`, which nsync documents as a valid unlocked mutex.
+pub(crate) const NSYNC_MU_INIT: ffi::nsync_mu = ffi::nsync_mu {
+    word: 0,
+    waiters: std::ptr::null_mut(),
+};
 
 /// A mutual exclusion primitive useful for protecting shared data
 ///
 /// This mutex will block threads waiting for the lock to become available.
 pub struct Mutex<T: ?Sized> {
     pub(super) _inner: UnsafeCell<ffi::nsync_mu>,
-    poison: std::sync::atomic::AtomicBool,
+    pub(super) poison: Flag,
+    pub(super) track: Tracker,
     data: UnsafeCell<T>,
 }
 
 impl<T: ?Sized> Mutex<T> {
-    pub(super) fn is_poisoned(&self, order: Ordering) -> bool {
-        self.poison.load(order)
+    /// Attempts to acquire this lock.
+    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
+        unsafe {
+            let ret = ffi::nsync_mu_trylock(self._inner.get());
+            if ret == 0 {
+                Err(TryLockError::WouldBlock)
+            } else {
+                self.track.acquired();
+                match MutexGuard::new(self) {
+                    Ok(guard) => Ok(guard),
+                    Err(e) => Err(TryLockError::Poisoned(e)),
+                }
+            }
+        }
+    }
+
+    /// Determines whether the mutex is poisoned.
+    ///
+    /// If another thread is active, the mutex can still become poisoned at
+    /// any time. You should not trust a `false` value for program correctness
+    /// without additional synchronization.
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    /// Aborts the process unless this mutex is held by some thread.
+    ///
+    /// nsync does not record which thread holds a mutex; see
+    /// [`assert_held_by_current_thread`](Mutex::assert_held_by_current_thread)
+    /// for a stricter check.
+    pub fn assert_held(&self) {
+        unsafe { ffi::nsync_mu_assert_held(self._inner.get()) }
+    }
+
+    /// Panics unless the current thread holds this mutex.
+    ///
+    /// Requires the `owner-tracking` feature, which records the holder of
+    /// every `Mutex` and write-locked `RwLock`.
+    #[cfg(feature = "owner-tracking")]
+    pub fn assert_held_by_current_thread(&self) {
+        assert!(
+            self.track.is_owned_by_current_thread(),
+            "mutex is not held by the current thread"
+        );
+    }
+
+    /// Returns a snapshot of this mutex's state and waiters, as reported by
+    /// `nsync_mu_debug_state_and_waiters`.
+    pub fn state_snapshot(&self) -> LockState {
+        debug::mu_state(self._inner.get())
+    }
+
+    /// Clear the poisoned state from a mutex.
+    ///
+    /// If the mutex is poisoned, it will remain poisoned until this function
+    /// is called. This allows recovering from a poisoned state and marking
+    /// that it has recovered.
+    pub fn clear_poison(&self) {
+        self.poison.clear();
     }
 }
 
@@ -55,9 +127,11 @@ impl<T: ?Sized> Drop for MutexGuard<'_, T> {
     fn drop(&mut self) {
         // If a panic occurred, mark the mutex as poisoned
         if std::thread::panicking() {
-            self.lock.poison.store(true, self.poison);
+            self.lock.poison.set(self.poison);
         }
 
+        self.lock.track.clear_owner();
+        self.lock.track.released();
         unsafe {
             ffi::nsync_mu_unlock(self.lock._inner.get());
         }
@@ -66,7 +140,8 @@ impl<T: ?Sized> Drop for MutexGuard<'_, T> {
 
 impl<'a, T: ?Sized + 'a> MutexGuard<'a, T> {
     pub(super) fn new(lock: &'a Mutex<T>) -> LockResult<MutexGuard<'a, T>> {
-        let is_poisoned = lock.poison.load(std::sync::atomic::Ordering::Relaxed);
+        lock.track.set_owner();
+        let is_poisoned = lock.poison.get();
         let guard = MutexGuard {
             lock,
             poison: std::sync::atomic::Ordering::Relaxed,
@@ -74,7 +149,7 @@ impl<'a, T: ?Sized + 'a> MutexGuard<'a, T> {
         };
 
         if is_poisoned {
-            Err(PoisonError::new(guard)) // Still return the guard, but wrapped in an error
+            Err(lock.poison.error(guard)) // Still return the guard, but wrapped in an error
         } else {
             Ok(guard)
         }
@@ -86,12 +161,28 @@ unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
 #[derive(Clone)]
 pub struct PoisonError<T> {
     guard: T,
+    #[cfg(feature = "poison-info")]
+    pub(crate) info: Option<std::sync::Arc<PoisonInfo>>,
 }
 
 impl<T> PoisonError<T> {
     /// Creates a new `PoisonError`.
     pub fn new(guard: T) -> PoisonError<T> {
-        PoisonError { guard }
+        PoisonError {
+            guard,
+            #[cfg(feature = "poison-info")]
+            info: None,
+        }
+    }
+    /// Returns details about the panic that poisoned the lock.
+    ///
+    /// These are only recorded with the `poison-info` feature enabled;
+    /// otherwise this always returns `None`.
+    pub fn info(&self) -> Option<&PoisonInfo> {
+        #[cfg(feature = "poison-info")]
+        return self.info.as_deref();
+        #[cfg(not(feature = "poison-info"))]
+        None
     }
     /// Consumes this error, returning the underlying guard.
     pub fn into_inner(self) -> T {
@@ -105,6 +196,14 @@ impl<T> PoisonError<T> {
     pub fn get_mut(&mut self) -> &mut T {
         &mut self.guard
     }
+    /// Replaces the guard, keeping the details of the panic.
+    pub(crate) fn map<U>(self, f: impl FnOnce(T) -> U) -> PoisonError<U> {
+        PoisonError {
+            guard: f(self.guard),
+            #[cfg(feature = "poison-info")]
+            info: self.info,
+        }
+    }
 }
 
 pub type LockResult<T> = Result<T, PoisonError<T>>;
@@ -176,51 +275,147 @@ impl<T> From<PoisonError<T>> for TryLockError<T> {
 
 impl<T> Mutex<T> {
     /// Creates a new mutex in an unlocked state ready for use.
-    pub fn new(t: T) -> Mutex<T> {
-        let mut mu = MaybeUninit::<ffi::nsync_mu>::uninit();
-        unsafe {
-            ffi::nsync_mu_init(mu.as_mut_ptr());
-            Mutex {
-                _inner: UnsafeCell::new(mu.assume_init()),
-                poison: std::sync::atomic::AtomicBool::new(false),
-                data: UnsafeCell::new(t),
-            }
+    ///
+    /// This is a `const fn`, so it can be used to initialize `static` items.
+    pub const fn new(t: T) -> Mutex<T> {
+        Mutex {
+            _inner: UnsafeCell::new(NSYNC_MU_INIT),
+            poison: Flag::new(),
+            track: Tracker::new(Kind::Mutex),
+            data: UnsafeCell::new(t),
+        }
+    }
+
+    /// Creates a new mutex at the given position in the lock hierarchy.
+    ///
+    /// See [`LockRank`] for the rules enforced in debug builds.
+    pub const fn with_rank(rank: LockRank, t: T) -> Mutex<T> {
+        Mutex {
+            _inner: UnsafeCell::new(NSYNC_MU_INIT),
+            poison: Flag::new(),
+            track: Tracker::ranked(Kind::Mutex, rank),
+            data: UnsafeCell::new(t),
+        }
+    }
+
+    /// Creates a new mutex registered under `name`.
+    ///
+    /// Named locks are listed by [`dump_all_locks`](crate::dump_all_locks)
+    /// while they are alive. They do a little extra bookkeeping on every
+    /// acquisition and release.
+    pub fn named(name: impl Into<String>, t: T) -> Mutex<T> {
+        Mutex {
+            _inner: UnsafeCell::new(NSYNC_MU_INIT),
+            poison: Flag::new(),
+            track: Tracker::named(name.into(), Kind::Mutex),
+            data: UnsafeCell::new(t),
         }
     }
 
     /// Acquires a mutex, blocking the current thread until it is able to do so.
     pub fn lock(&self) -> LockResult<MutexGuard<'_, T>> {
-        unsafe {
-            ffi::nsync_mu_lock(self._inner.get());
-        }
+        let mu = self._inner.get();
+        self.track.before_lock();
+        self.track.acquire(
+            mu,
+            || unsafe { ffi::nsync_mu_trylock(mu) != 0 },
+            || unsafe { ffi::nsync_mu_lock(mu) },
+        );
+        self.track.acquired();
         MutexGuard::new(self)
     }
 
-    /// Attempts to acquire this lock.
-    pub fn try_lock(&self) -> TryLockResult<MutexGuard<'_, T>> {
-        unsafe {
-            let ret = ffi::nsync_mu_trylock(self._inner.get());
-            if ret == 0 {
-                Err(TryLockError::WouldBlock)
-            } else {
-                match MutexGuard::new(self) {
-                    Ok(guard) => Ok(guard),
-                    Err(e) => Err(TryLockError::Poisoned(e)),
-                }
-            }
+    /// Acquires the mutex once `condition` holds for the protected data,
+    /// blocking the current thread until then.
+    ///
+    /// This is nsync's conditional critical section: rather than pairing the
+    /// mutex with a `Condvar`, the waiter's condition is re-evaluated whenever
+    /// the mutex is released, so no notification is needed. `condition` may
+    /// run on any thread that releases the mutex, hence the `Send` bound.
+    ///
+    /// If `condition` panics, the mutex is poisoned and the panic resumes in
+    /// the calling thread, which releases the mutex as it unwinds.
+    pub fn lock_when<F>(&self, condition: F) -> LockResult<MutexGuard<'_, T>>
+    where
+        F: FnMut(&T) -> bool + Send,
+    {
+        let (guard, _) = self.wait_when(condition, Time::no_deadline(), None);
+        if self.poison.get() {
+            Err(self.poison.error(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// Acquires the mutex once `condition` holds for the protected data, or
+    /// once `deadline` passes or `cancel` is notified, whichever comes first.
+    ///
+    /// The mutex is held on return either way; the outcome says whether
+    /// `condition` held. Panics in `condition` are handled as for
+    /// [`lock_when`](Mutex::lock_when).
+    pub fn lock_when_until<F>(
+        &self,
+        condition: F,
+        deadline: Time,
+        cancel: Option<&Note>,
+    ) -> LockResult<(MutexGuard<'_, T>, WaitOutcome)>
+    where
+        F: FnMut(&T) -> bool + Send,
+    {
+        let (guard, outcome) = self.wait_when(condition, deadline, cancel);
+        let outcome = outcome.wait_outcome();
+        if self.poison.get() {
+            Err(self.poison.error((guard, outcome)))
+        } else {
+            Ok((guard, outcome))
         }
     }
 
+    fn wait_when<F>(
+        &self,
+        mut condition: F,
+        deadline: Time,
+        cancel: Option<&Note>,
+    ) -> (MutexGuard<'_, T>, Outcome)
+    where
+        F: FnMut(&T) -> bool + Send,
+    {
+        let guard = match self.lock() {
+            Ok(guard) => guard,
+            Err(err) => err.into_inner(),
+        };
+        let data = self.data.get() as *const T;
+        let callback = Callback::new(
+            || condition(unsafe { &*data }),
+            || self.poison.set(std::sync::atomic::Ordering::Relaxed),
+        );
+        self.track.clear_owner();
+        let result = unsafe {
+            ffi::nsync_mu_wait_with_deadline(
+                self._inner.get(),
+                Some(callback.condition_fn()),
+                callback.arg(),
+                None,
+                deadline.as_raw(),
+                cancel.map_or(std::ptr::null_mut(), Note::as_ptr),
+            )
+        };
+        self.track.set_owner();
+        // Dropping `guard` while unwinding releases the mutex.
+        callback.resume();
+        (guard, Outcome::cancellable(result))
+    }
+
     /// Consumes this mutex, returning the underlying data.
     pub fn into_inner(self) -> LockResult<T>
     where
         T: Sized,
     {
-        let is_poisoned = self.poison.load(std::sync::atomic::Ordering::Relaxed);
+        let is_poisoned = self.poison.get();
         let data = self.data.into_inner();
 
         if is_poisoned {
-            Err(PoisonError::new(data))
+            Err(self.poison.error(data))
         } else {
             Ok(data)
         }
@@ -228,21 +423,41 @@ impl<T> Mutex<T> {
 
     /// Returns a mutable reference to the underlying data.
     pub fn get_mut(&mut self) -> LockResult<&mut T> {
-        let is_poisoned = self.poison.load(std::sync::atomic::Ordering::Relaxed);
+        let is_poisoned = self.poison.get();
         let data = self.data.get_mut();
 
         if is_poisoned {
-            Err(PoisonError::new(data))
+            Err(self.poison.error(data))
         } else {
             Ok(data)
         }
     }
 }
 
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Mutex<T> {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("Mutex");
+        match self.try_lock() {
+            Ok(guard) => d.field("data", &&*guard),
+            Err(TryLockError::Poisoned(err)) => d.field("data", &&**err.get_ref()),
+            Err(TryLockError::WouldBlock) => d.field("data", &format_args!("<locked>")),
+        };
+        d.field("poisoned", &self.is_poisoned());
+        d.finish_non_exhaustive()
+    }
+}
+
 /// A reader-writer lock
 pub struct RwLock<T: ?Sized> {
-    inner: UnsafeCell<ffi::nsync_mu>,
-    poison: std::sync::atomic::AtomicBool,
+    pub(super) inner: UnsafeCell<ffi::nsync_mu>,
+    pub(super) poison: Flag,
+    pub(super) track: Tracker,
     data: UnsafeCell<T>,
 }
 
@@ -251,6 +466,7 @@ unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
 
 pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
     lock: &'a RwLock<T>,
+    hold: HoldStart,
     // !Send
     _marker: PhantomData<*const ()>,
 }
@@ -266,32 +482,76 @@ unsafe impl<T: ?Sized + Sync> Sync for RwLockReadGuard<'_, T> {}
 unsafe impl<T: ?Sized + Sync> Sync for RwLockWriteGuard<'_, T> {}
 
 impl<T> RwLock<T> {
-    pub fn new(t: T) -> RwLock<T> {
-        let mut mu = MaybeUninit::<ffi::nsync_mu>::uninit();
-        unsafe {
-            ffi::nsync_mu_init(mu.as_mut_ptr());
-            RwLock {
-                inner: UnsafeCell::new(mu.assume_init()),
-                poison: std::sync::atomic::AtomicBool::new(false),
-                data: UnsafeCell::new(t),
-            }
+    /// Creates a new reader-writer lock in an unlocked state ready for use.
+    ///
+    /// This is a `const fn`, so it can be used to initialize `static` items.
+    pub const fn new(t: T) -> RwLock<T> {
+        RwLock {
+            inner: UnsafeCell::new(NSYNC_MU_INIT),
+            poison: Flag::new(),
+            track: Tracker::new(Kind::RwLock),
+            data: UnsafeCell::new(t),
         }
     }
 
-    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
-        unsafe {
-            ffi::nsync_mu_rlock(self.inner.get());
+    /// Creates a new reader-writer lock at the given position in the lock
+    /// hierarchy.
+    ///
+    /// See [`LockRank`] for the rules enforced in debug builds.
+    pub const fn with_rank(rank: LockRank, t: T) -> RwLock<T> {
+        RwLock {
+            inner: UnsafeCell::new(NSYNC_MU_INIT),
+            poison: Flag::new(),
+            track: Tracker::ranked(Kind::RwLock, rank),
+            data: UnsafeCell::new(t),
         }
+    }
+
+    /// Creates a new reader-writer lock registered under `name`.
+    ///
+    /// Named locks are listed by [`dump_all_locks`](crate::dump_all_locks)
+    /// while they are alive.
+    pub fn named(name: impl Into<String>, t: T) -> RwLock<T> {
+        RwLock {
+            inner: UnsafeCell::new(NSYNC_MU_INIT),
+            poison: Flag::new(),
+            track: Tracker::named(name.into(), Kind::RwLock),
+            data: UnsafeCell::new(t),
+        }
+    }
+
+    pub fn read(&self) -> LockResult<RwLockReadGuard<'_, T>> {
+        let mu = self.inner.get();
+        self.track.before_lock();
+        self.track.acquire(
+            mu,
+            || unsafe { ffi::nsync_mu_rtrylock(mu) != 0 },
+            || unsafe { ffi::nsync_mu_rlock(mu) },
+        );
+        self.track.acquired();
         RwLockReadGuard::new(self)
     }
 
-    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<'_, T>> {
+    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
+        let mu = self.inner.get();
+        self.track.before_lock();
+        self.track.acquire(
+            mu,
+            || unsafe { ffi::nsync_mu_trylock(mu) != 0 },
+            || unsafe { ffi::nsync_mu_lock(mu) },
+        );
+        self.track.acquired();
+        RwLockWriteGuard::new(self)
+    }
+
+    pub fn try_write(&self) -> TryLockResult<RwLockWriteGuard<'_, T>> {
         unsafe {
-            let ret = ffi::nsync_mu_rtrylock(self.inner.get());
+            let ret = ffi::nsync_mu_trylock(self.inner.get());
             if ret == 0 {
                 Err(TryLockError::WouldBlock)
             } else {
-                match RwLockReadGuard::new(self) {
+                self.track.acquired();
+                match RwLockWriteGuard::new(self) {
                     Ok(guard) => Ok(guard),
                     Err(e) => Err(TryLockError::Poisoned(e)),
                 }
@@ -299,38 +559,134 @@ impl<T> RwLock<T> {
         }
     }
 
-    pub fn write(&self) -> LockResult<RwLockWriteGuard<'_, T>> {
-        unsafe {
-            ffi::nsync_mu_lock(self.inner.get());
+    /// Consumes this lock, returning the underlying data.
+    pub fn into_inner(self) -> LockResult<T>
+    where
+        T: Sized,
+    {
+        let is_poisoned = self.poison.get();
+        let data = self.data.into_inner();
+
+        if is_poisoned {
+            Err(self.poison.error(data))
+        } else {
+            Ok(data)
         }
-        RwLockWriteGuard::new(self)
     }
 
-    pub fn try_write(&self) -> TryLockResult<RwLockWriteGuard<'_, T>> {
+    /// Returns a mutable reference to the underlying data.
+    pub fn get_mut(&mut self) -> LockResult<&mut T> {
+        let is_poisoned = self.poison.get();
+        let data = self.data.get_mut();
+
+        if is_poisoned {
+            Err(self.poison.error(data))
+        } else {
+            Ok(data)
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub fn try_read(&self) -> TryLockResult<RwLockReadGuard<'_, T>> {
         unsafe {
-            let ret = ffi::nsync_mu_trylock(self.inner.get());
+            let ret = ffi::nsync_mu_rtrylock(self.inner.get());
             if ret == 0 {
                 Err(TryLockError::WouldBlock)
             } else {
-                match RwLockWriteGuard::new(self) {
+                self.track.acquired();
+                match RwLockReadGuard::new(self) {
                     Ok(guard) => Ok(guard),
                     Err(e) => Err(TryLockError::Poisoned(e)),
                 }
             }
         }
     }
+
+    /// Determines whether the lock is poisoned.
+    ///
+    /// If another thread is active, the lock can still become poisoned at any
+    /// time. You should not trust a `false` value for program correctness
+    /// without additional synchronization.
+    pub fn is_poisoned(&self) -> bool {
+        self.poison.get()
+    }
+
+    /// Aborts the process unless this lock is held for writing by some thread.
+    pub fn assert_held(&self) {
+        unsafe { ffi::nsync_mu_assert_held(self.inner.get()) }
+    }
+
+    /// Aborts the process unless this lock is held for reading or writing by
+    /// some thread.
+    pub fn assert_read_held(&self) {
+        unsafe { ffi::nsync_mu_rassert_held(self.inner.get()) }
+    }
+
+    /// Returns whether this lock is held in read mode rather than write mode.
+    ///
+    /// The lock must be held; nsync aborts the process otherwise.
+    pub fn is_read_locked(&self) -> bool {
+        unsafe { ffi::nsync_mu_is_reader(self.inner.get()) != 0 }
+    }
+
+    /// Panics unless the current thread holds this lock for writing.
+    ///
+    /// Requires the `owner-tracking` feature.
+    #[cfg(feature = "owner-tracking")]
+    pub fn assert_held_by_current_thread(&self) {
+        assert!(
+            self.track.is_owned_by_current_thread(),
+            "lock is not write-locked by the current thread"
+        );
+    }
+
+    /// Returns a snapshot of this lock's state and waiters, as reported by
+    /// `nsync_mu_debug_state_and_waiters`.
+    pub fn state_snapshot(&self) -> LockState {
+        debug::mu_state(self.inner.get())
+    }
+
+    /// Clear the poisoned state from a lock.
+    ///
+    /// If the lock is poisoned, it will remain poisoned until this function
+    /// is called. This allows recovering from a poisoned state and marking
+    /// that it has recovered.
+    pub fn clear_poison(&self) {
+        self.poison.clear();
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> RwLock<T> {
+        RwLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("RwLock");
+        match self.try_read() {
+            Ok(guard) => d.field("data", &&*guard),
+            Err(TryLockError::Poisoned(err)) => d.field("data", &&**err.get_ref()),
+            Err(TryLockError::WouldBlock) => d.field("data", &format_args!("<locked>")),
+        };
+        d.field("poisoned", &self.is_poisoned());
+        d.finish_non_exhaustive()
+    }
 }
 
 impl<'a, T: ?Sized> RwLockReadGuard<'a, T> {
     fn new(lock: &'a RwLock<T>) -> LockResult<RwLockReadGuard<'a, T>> {
-        let is_poisoned = lock.poison.load(std::sync::atomic::Ordering::Relaxed);
+        let is_poisoned = lock.poison.get();
         let guard = RwLockReadGuard {
             lock,
+            hold: lock.track.start_shared_hold(),
             _marker: PhantomData,
         };
 
         if is_poisoned {
-            Err(PoisonError::new(guard))
+            Err(lock.poison.error(guard))
         } else {
             Ok(guard)
         }
@@ -338,8 +694,9 @@ impl<'a, T: ?Sized> RwLockReadGuard<'a, T> {
 }
 
 impl<'a, T: ?Sized> RwLockWriteGuard<'a, T> {
-    fn new(lock: &'a RwLock<T>) -> LockResult<RwLockWriteGuard<'a, T>> {
-        let is_poisoned = lock.poison.load(std::sync::atomic::Ordering::Relaxed);
+    pub(super) fn new(lock: &'a RwLock<T>) -> LockResult<RwLockWriteGuard<'a, T>> {
+        lock.track.set_owner();
+        let is_poisoned = lock.poison.get();
         let guard = RwLockWriteGuard {
             lock,
             poison: std::sync::atomic::Ordering::Relaxed,
@@ -347,7 +704,7 @@ impl<'a, T: ?Sized> RwLockWriteGuard<'a, T> {
         };
 
         if is_poisoned {
-            Err(PoisonError::new(guard))
+            Err(lock.poison.error(guard))
         } else {
             Ok(guard)
         }
@@ -378,6 +735,8 @@ impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
 
 impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
     fn drop(&mut self) {
+        self.lock.track.end_shared_hold(self.hold);
+        self.lock.track.released();
         unsafe {
             ffi::nsync_mu_runlock(self.lock.inner.get());
         }
@@ -387,9 +746,11 @@ impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
 impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
     fn drop(&mut self) {
         if std::thread::panicking() {
-            self.lock.poison.store(true, self.poison);
+            self.lock.poison.set(self.poison);
         }
 
+        self.lock.track.clear_owner();
+        self.lock.track.released();
         unsafe {
             ffi::nsync_mu_unlock(self.lock.inner.get());
         }
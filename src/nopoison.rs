@@ -0,0 +1,319 @@
+//! Locks without poisoning, in the style of `parking_lot`.
+//!
+//! These types skip the poison flag entirely: a panic while a guard is held
+//! simply releases the lock, and `lock()` returns the guard directly.
+
+use crate::condvar::WaitTimeoutResult;
+use crate::ffi;
+use crate::mutex::NSYNC_MU_INIT;
+use crate::note::{Note, WaitOutcome};
+use crate::time::{Duration, Time};
+use crate::track::Outcome;
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::time::Duration as StdDuration;
+
+/// A mutual exclusion primitive that does not track poisoning.
+pub struct Mutex<T: ?Sized> {
+    inner: UnsafeCell<ffi::nsync_mu>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+/// An RAII implementation of a "scoped lock" of a [`Mutex`].
+pub struct MutexGuard<'a, T: ?Sized + 'a> {
+    lock: &'a Mutex<T>,
+    // !Send
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for MutexGuard<'_, T> {}
+
+impl<T> Mutex<T> {
+    /// Creates a new mutex in an unlocked state ready for use.
+    pub const fn new(t: T) -> Mutex<T> {
+        Mutex {
+            inner: UnsafeCell::new(NSYNC_MU_INIT),
+            data: UnsafeCell::new(t),
+        }
+    }
+
+    /// Consumes this mutex, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// Acquires the mutex, blocking the current thread until it is able to do so.
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        unsafe {
+            ffi::nsync_mu_lock(self.inner.get());
+        }
+        MutexGuard {
+            lock: self,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Attempts to acquire the mutex without blocking.
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        if unsafe { ffi::nsync_mu_trylock(self.inner.get()) } == 0 {
+            return None;
+        }
+        Some(MutexGuard {
+            lock: self,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Mutex<T> {
+        Mutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::nsync_mu_unlock(self.lock.inner.get());
+        }
+    }
+}
+
+/// A reader-writer lock that does not track poisoning.
+pub struct RwLock<T: ?Sized> {
+    inner: UnsafeCell<ffi::nsync_mu>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+/// RAII structure used to release the shared read access of a [`RwLock`].
+pub struct RwLockReadGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+    // !Send
+    _marker: PhantomData<*const ()>,
+}
+
+/// RAII structure used to release the exclusive write access of a [`RwLock`].
+pub struct RwLockWriteGuard<'a, T: ?Sized + 'a> {
+    lock: &'a RwLock<T>,
+    // !Send
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for RwLockReadGuard<'_, T> {}
+unsafe impl<T: ?Sized + Sync> Sync for RwLockWriteGuard<'_, T> {}
+
+impl<T> RwLock<T> {
+    /// Creates a new reader-writer lock in an unlocked state ready for use.
+    pub const fn new(t: T) -> RwLock<T> {
+        RwLock {
+            inner: UnsafeCell::new(NSYNC_MU_INIT),
+            data: UnsafeCell::new(t),
+        }
+    }
+
+    /// Consumes this lock, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    /// Locks this lock with shared read access, blocking the current thread
+    /// until it can be acquired.
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        unsafe {
+            ffi::nsync_mu_rlock(self.inner.get());
+        }
+        RwLockReadGuard {
+            lock: self,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Attempts to acquire this lock with shared read access without blocking.
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        if unsafe { ffi::nsync_mu_rtrylock(self.inner.get()) } == 0 {
+            return None;
+        }
+        Some(RwLockReadGuard {
+            lock: self,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Locks this lock with exclusive write access, blocking the current
+    /// thread until it can be acquired.
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        unsafe {
+            ffi::nsync_mu_lock(self.inner.get());
+        }
+        RwLockWriteGuard {
+            lock: self,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Attempts to acquire this lock with exclusive write access without blocking.
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        if unsafe { ffi::nsync_mu_trylock(self.inner.get()) } == 0 {
+            return None;
+        }
+        Some(RwLockWriteGuard {
+            lock: self,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default> Default for RwLock<T> {
+    fn default() -> RwLock<T> {
+        RwLock::new(T::default())
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::nsync_mu_runlock(self.lock.inner.get());
+        }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::nsync_mu_unlock(self.lock.inner.get());
+        }
+    }
+}
+
+/// A condition variable for use with the poison-free [`Mutex`].
+///
+/// Waits take the guard by mutable reference and never report poisoning.
+#[derive(Default)]
+pub struct Condvar {
+    inner: crate::Condvar,
+}
+
+impl Condvar {
+    /// Creates a new condition variable which is ready to be waited on and notified.
+    pub const fn new() -> Condvar {
+        Condvar {
+            inner: crate::Condvar::new(),
+        }
+    }
+
+    /// Blocks the current thread until this condition variable receives a notification.
+    pub fn wait<T: ?Sized>(&self, guard: &mut MutexGuard<'_, T>) {
+        let cv = self.inner._inner.get();
+        let mu = guard.lock.inner.get();
+        self.inner.track.before_wait(mu as usize);
+        self.inner.track.wait(cv, || unsafe {
+            ffi::nsync_cv_wait(cv, mu);
+            Outcome::Woken
+        });
+    }
+
+    /// Waits on this condition variable for a notification, timing out after a specified duration.
+    pub fn wait_timeout<T: ?Sized>(
+        &self,
+        guard: &mut MutexGuard<'_, T>,
+        dur: StdDuration,
+    ) -> WaitTimeoutResult {
+        let deadline = Time::now() + Duration::from(dur);
+        let outcome = self.wait_deadline(guard, deadline, std::ptr::null_mut());
+        WaitTimeoutResult(outcome == Outcome::TimedOut)
+    }
+
+    /// Waits on this condition variable for a notification until `deadline`
+    /// passes or `cancel` is notified, whichever comes first.
+    pub fn wait_until<T: ?Sized>(
+        &self,
+        guard: &mut MutexGuard<'_, T>,
+        deadline: Time,
+        cancel: Option<&Note>,
+    ) -> WaitOutcome {
+        let cancel = cancel.map_or(std::ptr::null_mut(), Note::as_ptr);
+        self.wait_deadline(guard, deadline, cancel).wait_outcome()
+    }
+
+    fn wait_deadline<T: ?Sized>(
+        &self,
+        guard: &mut MutexGuard<'_, T>,
+        deadline: Time,
+        cancel: *mut ffi::nsync_note_s_,
+    ) -> Outcome {
+        let cv = self.inner._inner.get();
+        let mu = guard.lock.inner.get();
+        self.inner.track.before_wait(mu as usize);
+        self.inner.track.wait(cv, || unsafe {
+            let result = ffi::nsync_cv_wait_with_deadline(cv, mu, deadline.as_raw(), cancel);
+            Outcome::cancellable(result)
+        })
+    }
+
+    /// Wakes up one blocked thread on this condvar.
+    pub fn notify_one(&self) {
+        self.inner.notify_one();
+    }
+
+    /// Wakes up all blocked threads on this condvar.
+    pub fn notify_all(&self) {
+        self.inner.notify_all();
+    }
+}
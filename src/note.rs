@@ -1,10 +1,21 @@
+use crate::checked::{self, NoteChildren};
+use crate::events::{self, Event};
 use crate::ffi;
+use crate::mutex::NSYNC_MU_INIT;
+use crate::openmetrics::CounterSlot;
+use crate::span;
 use crate::time::Time;
+use crate::track::Outcome;
+use std::cell::UnsafeCell;
+use std::fmt;
 use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration as StdDuration, Instant};
 
 /// A note is a notification primitive that can be used to cancel waits
 pub struct Note {
     ptr: NonNull<ffi::nsync_note_s_>,
+    children: NoteChildren,
 }
 
 unsafe impl Send for Note {}
@@ -19,11 +30,13 @@ impl Note {
         let ptr = unsafe { ffi::nsync_note_new(parent_ptr, deadline.as_raw()) };
         Note {
             ptr: NonNull::new(ptr).expect("nsync_note_new returned null"),
+            children: NoteChildren::new(parent.map(|p| &p.children)),
         }
     }
 
     /// Notifies this note
     pub fn notify(&self) {
+        self.trace(Event::NoteNotify);
         unsafe { ffi::nsync_note_notify(self.ptr.as_ptr()) }
     }
 
@@ -34,25 +47,95 @@ impl Note {
 
     /// Waits for this note to be notified or until the deadline
     pub fn wait(&self, deadline: Time) -> bool {
-        unsafe { ffi::nsync_note_wait(self.ptr.as_ptr(), deadline.as_raw()) == 0 }
+        self.trace(Event::NoteWait);
+        // nsync_note_wait returns nonzero if the note was notified.
+        let timed_out = span::blocking(
+            "Note",
+            None,
+            "wait",
+            || unsafe { ffi::nsync_note_wait(self.ptr.as_ptr(), deadline.as_raw()) == 0 },
+            |&timed_out| Outcome::waited(timed_out),
+        );
+        self.trace(Event::NoteWoken {
+            notified: !timed_out,
+        });
+        timed_out
     }
 
     /// Returns the expiry time of this note
     pub fn expiry(&self) -> Time {
         unsafe { Time(ffi::nsync_note_expiry(self.ptr.as_ptr())) }
     }
+
+    /// The underlying nsync note, for passing to waits as a cancellation
+    /// note.
+    pub(crate) fn as_ptr(&self) -> *mut ffi::nsync_note_s_ {
+        self.ptr.as_ptr()
+    }
+
+    fn trace(&self, event: Event) {
+        let object = self.ptr.as_ptr() as usize;
+        events::record(event, object, || format!("Note {object:#x}"));
+    }
 }
 
 impl Drop for Note {
     fn drop(&mut self) {
-        unsafe { ffi::nsync_note_free(self.ptr.as_ptr()) }
+        if self.children.before_free() {
+            unsafe { ffi::nsync_note_free(self.ptr.as_ptr()) }
+        }
     }
 }
 
-/// A counter that can be waited on to reach zero
+/// How a wait with a deadline ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitOutcome {
+    /// What the wait was for happened.
+    Completed,
+    /// The deadline passed first.
+    TimedOut,
+    /// A cancellation note was notified first.
+    Cancelled,
+}
+
+/// The error returned by [`Counter::try_add`] and [`Counter::checked_sub`]
+/// when an update would take the counter out of range. The counter is left
+/// unchanged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CounterError {
+    /// The counter would have gone below zero.
+    BelowZero,
+    /// The counter would have exceeded `i32::MAX`, past which nsync takes
+    /// it to have gone below zero.
+    Overflow,
+    /// The counter would have been raised from zero after a wait, which
+    /// nsync treats as a fatal error.
+    RaisedAfterWait,
+}
+
+impl fmt::Display for CounterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CounterError::BelowZero => "counter would go below zero",
+            CounterError::Overflow => "counter would overflow",
+            CounterError::RaisedAfterWait => "counter would be raised from zero after a wait",
+        })
+    }
+}
+
+impl std::error::Error for CounterError {}
+
 /// A counter that can be waited on to reach zero
 pub struct Counter {
     ptr: NonNull<ffi::nsync_counter_s_>,
+    /// Serializes updates and the start of waits, so that checks cannot
+    /// race with them.
+    update: UnsafeCell<ffi::nsync_mu>,
+    /// Whether anyone has waited on the counter, even if only until a
+    /// deadline that passed.
+    waited: AtomicBool,
+    name: Option<String>,
+    stats: CounterSlot,
 }
 
 unsafe impl Send for Counter {}
@@ -64,11 +147,98 @@ impl Counter {
         let ptr = unsafe { ffi::nsync_counter_new(value) };
         Counter {
             ptr: NonNull::new(ptr).expect("nsync_counter_new returned null"),
+            update: UnsafeCell::new(NSYNC_MU_INIT),
+            waited: AtomicBool::new(false),
+            name: None,
+            stats: CounterSlot::new(),
         }
     }
+
+    /// Creates a new counter called `name`, which appears in traces and, with
+    /// the `metrics` feature, in `metrics::render_openmetrics`.
+    pub fn named(name: impl Into<String>, value: u32) -> Self {
+        let name = name.into();
+        let mut counter = Counter::new(value);
+        counter.stats = CounterSlot::named(&name, value);
+        counter.name = Some(name);
+        counter
+    }
+
     /// Adds delta to the counter and returns the new value
+    ///
+    /// nsync aborts the process if the counter would go below zero or past
+    /// `i32::MAX`, or be raised from zero after it has been waited on; use
+    /// [`try_add`](Counter::try_add) or [`checked_sub`](Counter::checked_sub)
+    /// when that can happen.
     pub fn add(&self, delta: i32) -> u32 {
-        unsafe { ffi::nsync_counter_add(self.ptr.as_ptr(), delta) }
+        self.updating(|| {
+            let waited = self.waited.load(Ordering::Relaxed);
+            checked::counter_add(|| self.value(), delta, waited);
+            self.add_raw(delta)
+        })
+    }
+
+    /// Adds delta to the counter and returns the new value, or an error if
+    /// the counter would go below zero or past `i32::MAX`, or be raised from
+    /// zero after it has been waited on. Any earlier wait counts, including
+    /// one that timed out.
+    pub fn try_add(&self, delta: i32) -> Result<u32, CounterError> {
+        self.updating(|| {
+            let old = self.value();
+            let value = old as i64 + delta as i64;
+            if value < 0 {
+                Err(CounterError::BelowZero)
+            } else if value > i32::MAX as i64 {
+                Err(CounterError::Overflow)
+            } else if delta > 0 && old == 0 && self.waited.load(Ordering::Relaxed) {
+                Err(CounterError::RaisedAfterWait)
+            } else {
+                Ok(self.add_raw(delta))
+            }
+        })
+    }
+
+    /// Subtracts `n` from the counter and returns the new value, or an error
+    /// if the counter is less than `n`.
+    pub fn checked_sub(&self, n: u32) -> Result<u32, CounterError> {
+        self.updating(|| {
+            let mut value = self.value();
+            if n > value {
+                return Err(CounterError::BelowZero);
+            }
+            // nsync takes an `i32` delta. Every intermediate value is above
+            // the final one, so waiters only wake if it is zero.
+            let mut left = n;
+            while left > 0 {
+                let step = left.min(i32::MAX as u32);
+                value = self.add_raw(-(step as i32));
+                left -= step;
+            }
+            Ok(value)
+        })
+    }
+
+    /// Runs `f` with updates to the counter locked out. The lock is
+    /// released even if `f` panics, as `checked` builds may.
+    fn updating<R>(&self, f: impl FnOnce() -> R) -> R {
+        struct Unlock(*mut ffi::nsync_mu);
+
+        impl Drop for Unlock {
+            fn drop(&mut self) {
+                unsafe { ffi::nsync_mu_unlock(self.0) }
+            }
+        }
+
+        unsafe { ffi::nsync_mu_lock(self.update.get()) };
+        let _unlock = Unlock(self.update.get());
+        f()
+    }
+
+    fn add_raw(&self, delta: i32) -> u32 {
+        let value = unsafe { ffi::nsync_counter_add(self.ptr.as_ptr(), delta) };
+        self.stats.added(value);
+        self.trace(Event::CounterAdd { delta, value });
+        value
     }
 
     /// Returns the current value of the counter
@@ -78,7 +248,42 @@ impl Counter {
 
     /// Waits until the counter reaches zero or the deadline expires
     pub fn wait(&self, deadline: Time) -> u32 {
-        unsafe { ffi::nsync_counter_wait(self.ptr.as_ptr(), deadline.as_raw()) }
+        self.updating(|| self.waited.store(true, Ordering::Relaxed));
+        self.trace(Event::CounterWait);
+        let start = self.stats.is_enabled().then(Instant::now);
+        let value = span::blocking(
+            "Counter",
+            self.name.as_deref(),
+            "wait",
+            || unsafe { ffi::nsync_counter_wait(self.ptr.as_ptr(), deadline.as_raw()) },
+            |&value| Outcome::waited(value != 0),
+        );
+        if let Some(start) = start {
+            self.stats.waited(start.elapsed(), value);
+        }
+        self.trace(Event::CounterWoken { value });
+        value
+    }
+
+    /// Waits until the counter reaches zero or `deadline` passes.
+    pub fn wait_until(&self, deadline: Time) -> WaitOutcome {
+        match self.wait(deadline) {
+            0 => WaitOutcome::Completed,
+            _ => WaitOutcome::TimedOut,
+        }
+    }
+
+    /// Waits until the counter reaches zero or `timeout` elapses.
+    pub fn wait_timeout(&self, timeout: StdDuration) -> WaitOutcome {
+        self.wait_until(Time::now() + timeout.into())
+    }
+
+    fn trace(&self, event: Event) {
+        let object = self.ptr.as_ptr() as usize;
+        events::record(event, object, || match &self.name {
+            Some(name) => name.clone(),
+            None => format!("Counter {object:#x}"),
+        });
     }
 }
 
@@ -87,3 +292,49 @@ impl Drop for Counter {
         unsafe { ffi::nsync_counter_free(self.ptr.as_ptr()) }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn try_add_keeps_the_counter_in_range() {
+        let counter = Counter::new(1);
+        assert_eq!(counter.try_add(-2), Err(CounterError::BelowZero));
+        assert_eq!(counter.try_add(i32::MAX), Err(CounterError::Overflow));
+        assert_eq!(counter.try_add(i32::MAX - 1), Ok(i32::MAX as u32));
+        assert_eq!(counter.try_add(1), Err(CounterError::Overflow));
+        assert_eq!(counter.value(), i32::MAX as u32);
+        assert_eq!(
+            counter.checked_sub(i32::MAX as u32 + 1),
+            Err(CounterError::BelowZero)
+        );
+        assert_eq!(counter.checked_sub(i32::MAX as u32), Ok(0));
+    }
+
+    #[test]
+    fn a_timed_out_wait_blocks_raising_from_zero() {
+        let counter = Counter::new(1);
+        assert_eq!(
+            counter.wait_timeout(StdDuration::from_millis(1)),
+            WaitOutcome::TimedOut
+        );
+        assert_eq!(counter.try_add(1), Ok(2));
+        assert_eq!(counter.try_add(-2), Ok(0));
+        assert_eq!(counter.try_add(1), Err(CounterError::RaisedAfterWait));
+        assert_eq!(counter.value(), 0);
+    }
+
+    #[test]
+    fn wait_returns_once_the_counter_reaches_zero() {
+        let counter = Counter::new(2);
+        thread::scope(|s| {
+            s.spawn(|| {
+                counter.add(-1);
+                counter.add(-1);
+            });
+            assert_eq!(counter.wait(Time::no_deadline()), 0);
+        });
+    }
+}
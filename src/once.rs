@@ -1,65 +1,290 @@
+use crate::callback::Callback;
 use crate::ffi;
+use crate::mutex::NSYNC_MU_INIT;
 use std::cell::UnsafeCell;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+const INCOMPLETE: u8 = 0;
+const POISONED: u8 = 1;
+const COMPLETE: u8 = 2;
 
 /// A synchronization primitive which can be used to run a one-time global
 /// initialization.
-#[derive(Default)]
+///
+/// The first call runs through `nsync_run_once_arg`. If the closure panics,
+/// the panic is caught before it reaches nsync, the `Once` is poisoned and
+/// the panic resumes in the caller once nsync has returned. nsync's once is
+/// spent by then, so [`call_once_force`](Once::call_once_force) retries run
+/// under a mutex instead.
 pub struct Once {
     inner: UnsafeCell<ffi::nsync_once>,
-    done: AtomicBool,
+    state: AtomicU8,
+    /// Serializes retries after poisoning, and protects `state` changes for
+    /// [`wait`](Once::wait).
+    mu: UnsafeCell<ffi::nsync_mu>,
+    cv: UnsafeCell<ffi::nsync_cv>,
 }
 
 unsafe impl Send for Once {}
 unsafe impl Sync for Once {}
 
+/// State passed to the closure of [`Once::call_once_force`].
+#[derive(Debug)]
+pub struct OnceState {
+    poisoned: bool,
+}
+
+impl OnceState {
+    /// Returns `true` if a previous initialization closure panicked.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+}
+
 impl Once {
     pub const fn new() -> Once {
         Once {
             inner: UnsafeCell::new(0),
-            done: AtomicBool::new(false),
+            state: AtomicU8::new(INCOMPLETE),
+            mu: UnsafeCell::new(NSYNC_MU_INIT),
+            cv: UnsafeCell::new(ffi::nsync_cv {
+                word: 0,
+                waiters: std::ptr::null_mut(),
+            }),
         }
     }
 
     /// Performs an initialization routine idempotently.
+    ///
+    /// # Panics
+    ///
+    /// Panics if an earlier initialization closure panicked, poisoning this
+    /// `Once`. If `f` panics, the panic propagates to the caller and the
+    /// `Once` is poisoned.
     pub fn call_once<F>(&self, f: F)
     where
         F: FnOnce(),
     {
-        if self.done.load(Ordering::Acquire) {
+        if self.is_completed() {
+            return;
+        }
+
+        self.call_once_slow(false, |_| {
+            f();
+            true
+        });
+    }
+
+    /// Performs an initialization routine idempotently, even if an earlier
+    /// initialization closure panicked.
+    ///
+    /// `f` learns through [`OnceState::is_poisoned`] whether it is retrying
+    /// after a panic. If `f` panics too, the `Once` stays poisoned.
+    pub fn call_once_force<F>(&self, f: F)
+    where
+        F: FnOnce(&OnceState),
+    {
+        if self.is_completed() {
             return;
         }
 
-        self.call_once_slow(f);
+        self.call_once_slow(true, |state| {
+            f(state);
+            true
+        });
     }
 
+    /// Runs `f` unless initialization has completed. `f` returns whether it
+    /// completed initialization; if not, a later call runs its closure.
+    /// Unless `force` is set, panics if this `Once` is poisoned.
     #[cold]
-    fn call_once_slow<F>(&self, f: F)
+    pub(crate) fn call_once_slow<F>(&self, force: bool, f: F)
     where
-        F: FnOnce(),
+        F: FnOnce(&OnceState) -> bool,
     {
-        struct Closure<F: FnOnce()> {
-            func: Option<F>,
+        let mut func = Some(f);
+        let callback = Callback::new(
+            || {
+                let f = func.take().unwrap();
+                let done = f(&OnceState { poisoned: false });
+                self.finish(if done { COMPLETE } else { INCOMPLETE });
+            },
+            || self.finish(POISONED),
+        );
+        unsafe {
+            ffi::nsync_run_once_arg(self.inner.get(), Some(callback.void_fn()), callback.arg());
         }
-        unsafe extern "C" fn run_closure<F: FnOnce()>(p: *mut std::os::raw::c_void) {
-            let closure = unsafe { &mut *(p as *mut Closure<F>) };
-            let func = closure.func.take().unwrap();
-            func();
+        callback.resume();
+        if let Some(f) = func {
+            // nsync already ran an earlier closure, which completed, panicked
+            // or left initialization for a later call.
+            self.retry(force, f);
         }
-        let mut closure = Closure { func: Some(f) };
+    }
+
+    /// Runs `f` after nsync's once is spent, unless initialization has
+    /// completed or this `Once` is poisoned and `force` is not set. Holds
+    /// `mu` so that only one retry runs at a time.
+    #[cold]
+    fn retry<F>(&self, force: bool, f: F)
+    where
+        F: FnOnce(&OnceState) -> bool,
+    {
+        unsafe { ffi::nsync_mu_lock(self.mu.get()) };
+        let state = self.state.load(Ordering::Acquire);
+        if state == COMPLETE {
+            unsafe { ffi::nsync_mu_unlock(self.mu.get()) };
+            return;
+        }
+        let poisoned = state == POISONED;
+        if poisoned && !force {
+            unsafe { ffi::nsync_mu_unlock(self.mu.get()) };
+            panic!("Once instance has previously been poisoned");
+        }
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&OnceState { poisoned })));
+        let state = match result {
+            Ok(true) => COMPLETE,
+            Ok(false) => state,
+            Err(_) => POISONED,
+        };
+        self.state.store(state, Ordering::Release);
+        unsafe {
+            ffi::nsync_cv_broadcast(self.cv.get());
+            ffi::nsync_mu_unlock(self.mu.get());
+        }
+        if let Err(payload) = result {
+            panic::resume_unwind(payload);
+        }
+    }
+
+    /// Records the outcome of the first attempt and wakes
+    /// [`wait`](Once::wait)ers.
+    fn finish(&self, state: u8) {
         unsafe {
-            ffi::nsync_run_once_arg(
-                self.inner.get(),
-                Some(run_closure::<F>),
-                &mut closure as *mut _ as *mut std::os::raw::c_void,
-            );
+            ffi::nsync_mu_lock(self.mu.get());
+            self.state.store(state, Ordering::Release);
+            ffi::nsync_cv_broadcast(self.cv.get());
+            ffi::nsync_mu_unlock(self.mu.get());
         }
+    }
 
-        self.done.store(true, Ordering::Release);
+    /// Blocks the current thread until initialization has completed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if this `Once` is poisoned, or becomes poisoned while waiting.
+    pub fn wait(&self) {
+        if self.is_completed() {
+            return;
+        }
+        unsafe { ffi::nsync_mu_lock(self.mu.get()) };
+        loop {
+            match self.state.load(Ordering::Acquire) {
+                COMPLETE => break,
+                POISONED => {
+                    unsafe { ffi::nsync_mu_unlock(self.mu.get()) };
+                    panic!("Once instance has previously been poisoned");
+                }
+                _ => unsafe { ffi::nsync_cv_wait(self.cv.get(), self.mu.get()) },
+            }
+        }
+        unsafe { ffi::nsync_mu_unlock(self.mu.get()) };
     }
 
     /// Returns `true` if some `call_once` call has completed successfully.
     pub fn is_completed(&self) -> bool {
-        self.done.load(Ordering::Acquire)
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+
+    /// Returns `true` if an initialization closure panicked and none has
+    /// completed since.
+    pub fn is_poisoned(&self) -> bool {
+        self.state.load(Ordering::Acquire) == POISONED
+    }
+}
+
+impl Default for Once {
+    fn default() -> Once {
+        Once::new()
+    }
+}
+
+impl fmt::Debug for Once {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Once")
+            .field("completed", &self.is_completed())
+            .field("poisoned", &self.is_poisoned())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::thread;
+
+    fn panics(f: impl FnOnce()) -> bool {
+        panic::catch_unwind(AssertUnwindSafe(f)).is_err()
+    }
+
+    #[test]
+    fn runs_the_closure_once_across_threads() {
+        let once = Once::new();
+        let runs = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    once.call_once(|| {
+                        runs.fetch_add(1, Ordering::Relaxed);
+                    })
+                });
+            }
+        });
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+        assert!(once.is_completed());
+    }
+
+    #[test]
+    fn a_panic_poisons_until_a_forced_call_completes() {
+        let once = Once::new();
+        assert!(panics(|| once.call_once(|| panic!("first attempt"))));
+        assert!(once.is_poisoned());
+        assert!(panics(|| once.call_once(|| {})));
+        assert!(panics(|| once.wait()));
+
+        assert!(panics(|| once.call_once_force(|_| panic!("second attempt"))));
+        assert!(once.is_poisoned());
+
+        let mut saw_poison = false;
+        once.call_once_force(|state| saw_poison = state.is_poisoned());
+        assert!(saw_poison);
+        assert!(once.is_completed());
+        once.call_once(|| unreachable!());
+    }
+
+    #[test]
+    fn unfinished_initialization_is_run_again() {
+        let once = Once::new();
+        once.call_once_slow(false, |_| false);
+        assert!(!once.is_completed());
+        assert!(!once.is_poisoned());
+        let mut ran = false;
+        once.call_once(|| ran = true);
+        assert!(ran);
+        assert!(once.is_completed());
+    }
+
+    #[test]
+    fn wait_blocks_until_another_thread_completes() {
+        let once = Once::new();
+        thread::scope(|s| {
+            let waiter = s.spawn(|| once.wait());
+            thread::sleep(std::time::Duration::from_millis(10));
+            once.call_once(|| {});
+            waiter.join().unwrap();
+        });
     }
 }
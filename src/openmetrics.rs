@@ -0,0 +1,367 @@
+//! A Prometheus/OpenMetrics text exporter for named locks, condition
+//! variables and counters, enabled by the `metrics` feature.
+//!
+//! Lock and condition variable metrics come from the contention profiler.
+//! Counters keep their own statistics, but only when created with
+//! `Counter::named`.
+
+#[cfg(feature = "metrics")]
+use crate::nopoison;
+#[cfg(feature = "metrics")]
+use crate::profiling::{self, Histogram, Profile, nanos};
+#[cfg(feature = "metrics")]
+use crate::registry::Kind;
+use std::time::Duration as StdDuration;
+#[cfg(feature = "metrics")]
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    sync::{Arc, Weak},
+};
+
+/// A named counter's statistics. Compiles to nothing without the `metrics`
+/// feature.
+pub(crate) struct CounterSlot {
+    #[cfg(feature = "metrics")]
+    stats: Option<Arc<CounterStats>>,
+}
+
+#[cfg(feature = "metrics")]
+struct CounterStats {
+    name: String,
+    /// The counter's value after its last update.
+    value: AtomicU64,
+    adds: AtomicU64,
+    timeouts: AtomicU64,
+    wait: Histogram,
+}
+
+#[cfg(feature = "metrics")]
+static COUNTERS: nopoison::Mutex<Vec<Weak<CounterStats>>> = nopoison::Mutex::new(Vec::new());
+
+impl CounterSlot {
+    pub(crate) const fn new() -> CounterSlot {
+        CounterSlot {
+            #[cfg(feature = "metrics")]
+            stats: None,
+        }
+    }
+
+    /// Registers a counter called `name` that starts at `value`.
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub(crate) fn named(name: &str, value: u32) -> CounterSlot {
+        #[cfg(feature = "metrics")]
+        {
+            let stats = Arc::new(CounterStats {
+                name: name.to_string(),
+                value: AtomicU64::new(value as u64),
+                adds: AtomicU64::new(0),
+                timeouts: AtomicU64::new(0),
+                wait: Histogram::new(),
+            });
+            let mut counters = COUNTERS.lock();
+            counters.retain(|c| c.strong_count() > 0);
+            counters.push(Arc::downgrade(&stats));
+            CounterSlot { stats: Some(stats) }
+        }
+        #[cfg(not(feature = "metrics"))]
+        CounterSlot::new()
+    }
+
+    /// Called after an add left the counter at `value`.
+    #[inline]
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub(crate) fn added(&self, value: u32) {
+        #[cfg(feature = "metrics")]
+        if let Some(stats) = &self.stats {
+            stats.adds.fetch_add(1, Ordering::Relaxed);
+            stats.value.store(value as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Called after a wait that blocked for `wait` and returned `value`.
+    #[inline]
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub(crate) fn waited(&self, wait: StdDuration, value: u32) {
+        #[cfg(feature = "metrics")]
+        if let Some(stats) = &self.stats {
+            stats.wait.record(nanos(wait));
+            if value != 0 {
+                stats.timeouts.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Whether waits need timing.
+    #[inline]
+    pub(crate) fn is_enabled(&self) -> bool {
+        #[cfg(feature = "metrics")]
+        return self.stats.is_some();
+        #[cfg(not(feature = "metrics"))]
+        false
+    }
+}
+
+/// Histogram bucket bounds: powers of four from about 1µs to about 68s, in
+/// histogram bucket indices (bounds of `2^b` nanoseconds).
+#[cfg(feature = "metrics")]
+const BUCKETS: [usize; 14] = [10, 12, 14, 16, 18, 20, 22, 24, 26, 28, 30, 32, 34, 36];
+
+/// Renders the metrics of every live named lock, condition variable and
+/// counter in the OpenMetrics text format, ready to serve from a `/metrics`
+/// endpoint.
+///
+/// Locks and condition variables are labelled with their name and kind.
+/// Unnamed locks and counters are not included.
+#[cfg(feature = "metrics")]
+pub fn render_openmetrics() -> String {
+    let mut profiles: Vec<Arc<Profile>> = profiling::live_profiles()
+        .into_iter()
+        .filter(|p| p.named)
+        .collect();
+    profiles.sort_by(|a, b| (a.kind.as_str(), &a.name).cmp(&(b.kind.as_str(), &b.name)));
+    let mut counters: Vec<Arc<CounterStats>> =
+        COUNTERS.lock().iter().filter_map(Weak::upgrade).collect();
+    counters.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut out = String::new();
+    let lock_labels = |p: &Profile| format!("name=\"{}\",kind=\"{}\"", escape(&p.name), p.kind);
+    let counter_labels =
+        |c: &CounterStats| format!("name=\"{}\",kind=\"Counter\"", escape(&c.name));
+
+    family(
+        &mut out,
+        "nsync_acquisitions",
+        "counter",
+        "Blocking lock acquisitions and condition variable waits.",
+    );
+    for p in &profiles {
+        sample(
+            &mut out,
+            "nsync_acquisitions_total",
+            &lock_labels(p),
+            p.acquisitions.load(Ordering::Relaxed),
+        );
+    }
+
+    family(
+        &mut out,
+        "nsync_contended_acquisitions",
+        "counter",
+        "Blocking acquisitions that found the lock held.",
+    );
+    for p in profiles.iter().filter(|p| p.kind != Kind::Condvar) {
+        sample(
+            &mut out,
+            "nsync_contended_acquisitions_total",
+            &lock_labels(p),
+            p.contended.load(Ordering::Relaxed),
+        );
+    }
+
+    family(
+        &mut out,
+        "nsync_timeouts",
+        "counter",
+        "Waits that reached their deadline.",
+    );
+    for p in profiles.iter().filter(|p| p.kind == Kind::Condvar) {
+        sample(
+            &mut out,
+            "nsync_timeouts_total",
+            &lock_labels(p),
+            p.timeouts.load(Ordering::Relaxed),
+        );
+    }
+    for c in &counters {
+        sample(
+            &mut out,
+            "nsync_timeouts_total",
+            &counter_labels(c),
+            c.timeouts.load(Ordering::Relaxed),
+        );
+    }
+
+    family(
+        &mut out,
+        "nsync_cancellations",
+        "counter",
+        "Waits cut short by a cancellation note.",
+    );
+    for p in profiles.iter().filter(|p| p.kind == Kind::Condvar) {
+        sample(
+            &mut out,
+            "nsync_cancellations_total",
+            &lock_labels(p),
+            p.cancellations.load(Ordering::Relaxed),
+        );
+    }
+
+    family(
+        &mut out,
+        "nsync_wait_seconds",
+        "histogram",
+        "Time spent blocked acquiring a lock or waiting.",
+    );
+    for p in &profiles {
+        histogram(&mut out, "nsync_wait_seconds", &lock_labels(p), &p.wait);
+    }
+    for c in &counters {
+        histogram(&mut out, "nsync_wait_seconds", &counter_labels(c), &c.wait);
+    }
+
+    family(
+        &mut out,
+        "nsync_hold_seconds",
+        "histogram",
+        "Time a lock was held.",
+    );
+    for p in profiles.iter().filter(|p| p.kind != Kind::Condvar) {
+        histogram(&mut out, "nsync_hold_seconds", &lock_labels(p), &p.hold);
+    }
+
+    family(
+        &mut out,
+        "nsync_counter_adds",
+        "counter",
+        "Calls to Counter::add.",
+    );
+    for c in &counters {
+        sample(
+            &mut out,
+            "nsync_counter_adds_total",
+            &counter_labels(c),
+            c.adds.load(Ordering::Relaxed),
+        );
+    }
+
+    family(
+        &mut out,
+        "nsync_counter_value",
+        "gauge",
+        "The counter's current value.",
+    );
+    for c in &counters {
+        sample(
+            &mut out,
+            "nsync_counter_value",
+            &counter_labels(c),
+            c.value.load(Ordering::Relaxed),
+        );
+    }
+
+    out.push_str("# EOF\n");
+    out
+}
+
+#[cfg(feature = "metrics")]
+fn family(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    if name.ends_with("_seconds") {
+        let _ = writeln!(out, "# UNIT {name} seconds");
+    }
+    let _ = writeln!(out, "# HELP {name} {help}");
+}
+
+#[cfg(feature = "metrics")]
+fn sample(out: &mut String, name: &str, labels: &str, value: u64) {
+    let _ = writeln!(out, "{name}{{{labels}}} {value}");
+}
+
+#[cfg(feature = "metrics")]
+fn histogram(out: &mut String, name: &str, labels: &str, h: &Histogram) {
+    // `+Inf` and `_count` come from the same read of the buckets as the
+    // finite bounds, so none exceeds them even while `record` runs.
+    let counts = h.cumulative_counts();
+    for b in BUCKETS {
+        let le = (1u64 << b) as f64 / 1e9;
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{le}\"}} {}", counts[b]);
+    }
+    let count = counts[63];
+    let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {count}");
+    let _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+    let _ = writeln!(out, "{name}_sum{{{labels}}} {}", h.sum() as f64 / 1e9);
+}
+
+#[cfg(feature = "metrics")]
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+    use crate::{Counter, Mutex};
+
+    #[test]
+    fn renders_cumulative_histogram_buckets() {
+        let h = Histogram::new();
+        for nanos in [500, 5_000, 100_000_000_000] {
+            h.record(nanos);
+        }
+        let mut out = String::new();
+        histogram(&mut out, "x_seconds", "name=\"h\"", &h);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), BUCKETS.len() + 3);
+        assert_eq!(
+            lines[0],
+            "x_seconds_bucket{name=\"h\",le=\"0.000001024\"} 1"
+        );
+        assert_eq!(
+            lines[1],
+            "x_seconds_bucket{name=\"h\",le=\"0.000004096\"} 1"
+        );
+        assert_eq!(
+            lines[2],
+            "x_seconds_bucket{name=\"h\",le=\"0.000016384\"} 2"
+        );
+        assert_eq!(
+            lines[BUCKETS.len() - 1],
+            "x_seconds_bucket{name=\"h\",le=\"68.719476736\"} 2"
+        );
+        assert_eq!(
+            &lines[BUCKETS.len()..],
+            [
+                "x_seconds_bucket{name=\"h\",le=\"+Inf\"} 3",
+                "x_seconds_count{name=\"h\"} 3",
+                "x_seconds_sum{name=\"h\"} 100.0000055",
+            ]
+        );
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+    }
+
+    #[test]
+    fn renders_named_locks_and_counters() {
+        let lock = Mutex::named("openmetrics lock", ());
+        drop(lock.lock().unwrap());
+        let counter = Counter::named("openmetrics \"counter\"", 2);
+        counter.add(-1);
+
+        let out = render_openmetrics();
+        let lock_labels = "{name=\"openmetrics lock\",kind=\"Mutex\"}";
+        let counter_labels = "{name=\"openmetrics \\\"counter\\\"\",kind=\"Counter\"}";
+        for line in [
+            format!("nsync_acquisitions_total{lock_labels} 1"),
+            format!("nsync_hold_seconds_count{lock_labels} 1"),
+            format!("nsync_counter_adds_total{counter_labels} 1"),
+            format!("nsync_counter_value{counter_labels} 1"),
+        ] {
+            assert!(
+                out.lines().any(|l| l == line),
+                "missing {line:?} in:\n{out}"
+            );
+        }
+        assert!(
+            out.contains(
+                "# TYPE nsync_wait_seconds histogram\n# UNIT nsync_wait_seconds seconds\n"
+            )
+        );
+        assert!(out.ends_with("# EOF\n"));
+    }
+}
@@ -0,0 +1,125 @@
+use crate::mutex::PoisonError;
+use crate::time::Time;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "poison-info")]
+use std::{cell::RefCell, sync::Arc};
+
+/// Details about the panic that poisoned a lock.
+///
+/// Only recorded when the `poison-info` feature is enabled; see
+/// [`PoisonError::info`].
+#[derive(Debug, Clone)]
+pub struct PoisonInfo {
+    /// The thread that panicked while holding the lock.
+    pub thread_id: std::thread::ThreadId,
+    /// The name of that thread, if it had one.
+    pub thread_name: Option<String>,
+    /// Where the panic was raised, as `file:line:column`. Only known if
+    /// `install_panic_hook` was called first.
+    pub location: Option<String>,
+    /// The panic message, if the payload was a string. Only known if
+    /// `install_panic_hook` was called first.
+    pub message: Option<String>,
+    /// When the lock was poisoned.
+    pub time: Time,
+}
+
+/// The poison state of a lock, plus the details of the panic that set it.
+pub(crate) struct Flag {
+    failed: AtomicBool,
+    #[cfg(feature = "poison-info")]
+    info: crate::nopoison::Mutex<Option<Arc<PoisonInfo>>>,
+}
+
+impl Flag {
+    pub(crate) const fn new() -> Flag {
+        Flag {
+            failed: AtomicBool::new(false),
+            #[cfg(feature = "poison-info")]
+            info: crate::nopoison::Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn get(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn clear(&self) {
+        #[cfg(feature = "poison-info")]
+        self.info.lock().take();
+        self.failed.store(false, Ordering::Relaxed);
+    }
+
+    /// Marks the lock as poisoned by the current, panicking thread.
+    pub(crate) fn set(&self, order: Ordering) {
+        #[cfg(feature = "poison-info")]
+        {
+            // Keep the first panic: that is the one that broke the invariant.
+            let mut info = self.info.lock();
+            if info.is_none() {
+                *info = Some(Arc::new(PoisonInfo::current()));
+            }
+        }
+        self.failed.store(true, order);
+    }
+
+    /// Wraps `guard` in a `PoisonError` carrying this flag's details.
+    pub(crate) fn error<G>(&self, guard: G) -> PoisonError<G> {
+        #[allow(unused_mut)]
+        let mut err = PoisonError::new(guard);
+        #[cfg(feature = "poison-info")]
+        {
+            err.info = self.info.lock().clone();
+        }
+        err
+    }
+}
+
+#[cfg(feature = "poison-info")]
+thread_local! {
+    static LAST_PANIC: RefCell<(Option<String>, Option<String>)> = const { RefCell::new((None, None)) };
+}
+
+#[cfg(feature = "poison-info")]
+static HOOK: std::sync::Once = std::sync::Once::new();
+
+/// Installs a panic hook that remembers each thread's latest panic location
+/// and message for [`PoisonInfo`], then calls the hook that was installed
+/// before.
+///
+/// Without it, [`PoisonInfo::location`] and [`PoisonInfo::message`] are
+/// always `None`. Call it once at startup, after installing any panic hook
+/// of your own: a hook set later replaces this one. Later calls do nothing.
+#[cfg(feature = "poison-info")]
+pub fn install_panic_hook() {
+    HOOK.call_once(|| {
+        let prev = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let location = info.location().map(|l| l.to_string());
+            let payload = info.payload();
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned());
+            let _ = LAST_PANIC.try_with(|last| *last.borrow_mut() = (location, message));
+            prev(info);
+        }));
+    });
+}
+
+#[cfg(feature = "poison-info")]
+impl PoisonInfo {
+    fn current() -> PoisonInfo {
+        let thread = std::thread::current();
+        let (location, message) = LAST_PANIC
+            .try_with(|last| last.borrow().clone())
+            .unwrap_or_default();
+        PoisonInfo {
+            thread_id: thread.id(),
+            thread_name: thread.name().map(str::to_string),
+            location,
+            message,
+            time: Time::now(),
+        }
+    }
+}
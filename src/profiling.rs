@@ -0,0 +1,375 @@
+//! Per-lock wait- and hold-time histograms, enabled by the `profiling`
+//! feature.
+//!
+//! Each blocking acquisition first tries the lock without blocking. If that
+//! fails the acquisition counts as contended and the time spent blocked is
+//! recorded. Hold times are measured from acquisition to release.
+
+use crate::registry::Kind;
+use crate::track::Outcome;
+#[cfg(feature = "profiling")]
+use std::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicU64, Ordering},
+    sync::{Arc, OnceLock, Weak},
+    time::{Duration as StdDuration, Instant},
+};
+
+/// A lock's profile, created on first use. Compiles to nothing without the
+/// `profiling` feature.
+pub(crate) struct ProfileSlot {
+    #[cfg(feature = "profiling")]
+    profile: OnceLock<Arc<Profile>>,
+    /// When the current exclusive hold began. Only the exclusive holder
+    /// touches this.
+    #[cfg(feature = "profiling")]
+    exclusive_since: UnsafeCell<Option<Instant>>,
+}
+
+impl ProfileSlot {
+    pub(crate) const fn new() -> ProfileSlot {
+        ProfileSlot {
+            #[cfg(feature = "profiling")]
+            profile: OnceLock::new(),
+            #[cfg(feature = "profiling")]
+            exclusive_since: UnsafeCell::new(None),
+        }
+    }
+
+    /// Acquires a lock with `lock`, first trying `try_lock` to find out
+    /// whether the lock is contended when profiling or tracing is enabled.
+    #[inline]
+    #[cfg_attr(not(feature = "profiling"), allow(unused_variables))]
+    pub(crate) fn acquire(
+        &self,
+        kind: Kind,
+        name: Option<&str>,
+        try_lock: impl FnOnce() -> bool,
+        lock: impl FnOnce(),
+    ) {
+        #[cfg(feature = "profiling")]
+        {
+            let profile = self.get(kind, name);
+            profile.acquisitions.fetch_add(1, Ordering::Relaxed);
+            if try_lock() {
+                profile.wait.record(0);
+            } else {
+                profile.contended.fetch_add(1, Ordering::Relaxed);
+                let start = Instant::now();
+                lock();
+                let wait = nanos(start.elapsed());
+                profile.wait.record(wait);
+                #[cfg(feature = "contention-profile")]
+                crate::contention::record(wait);
+            }
+        }
+        #[cfg(not(feature = "profiling"))]
+        if !(cfg!(feature = "tracing") && try_lock()) {
+            lock();
+        }
+    }
+
+    /// Counts an acquisition that found the lock free without going through
+    /// [`acquire`](Self::acquire).
+    #[inline]
+    #[cfg_attr(not(feature = "profiling"), allow(unused_variables))]
+    pub(crate) fn uncontended(&self, kind: Kind, name: Option<&str>) {
+        #[cfg(feature = "profiling")]
+        {
+            let profile = self.get(kind, name);
+            profile.acquisitions.fetch_add(1, Ordering::Relaxed);
+            profile.wait.record(0);
+        }
+    }
+
+    /// Runs `wait`, recording how long it blocked and whether it timed out.
+    #[inline]
+    #[cfg_attr(not(feature = "profiling"), allow(unused_variables))]
+    pub(crate) fn time_wait(
+        &self,
+        kind: Kind,
+        name: Option<&str>,
+        wait: impl FnOnce() -> Outcome,
+    ) -> Outcome {
+        #[cfg(feature = "profiling")]
+        {
+            let profile = self.get(kind, name);
+            profile.acquisitions.fetch_add(1, Ordering::Relaxed);
+            let start = Instant::now();
+            let outcome = wait();
+            profile.wait.record(nanos(start.elapsed()));
+            let cut_short = match outcome {
+                Outcome::TimedOut => Some(&profile.timeouts),
+                Outcome::Cancelled => Some(&profile.cancellations),
+                _ => None,
+            };
+            if let Some(count) = cut_short {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+            outcome
+        }
+        #[cfg(not(feature = "profiling"))]
+        wait()
+    }
+
+    /// Called by the exclusive holder once it holds the lock.
+    #[inline]
+    pub(crate) fn begin_exclusive_hold(&self) {
+        #[cfg(feature = "profiling")]
+        unsafe {
+            *self.exclusive_since.get() = Some(Instant::now());
+        }
+    }
+
+    /// Called by the exclusive holder just before it releases the lock.
+    #[inline]
+    pub(crate) fn end_exclusive_hold(&self) {
+        #[cfg(feature = "profiling")]
+        if let Some(start) = unsafe { (*self.exclusive_since.get()).take() } {
+            self.end_hold(HoldStart { at: start });
+        }
+    }
+
+    /// Returns the time at which a shared hold starts, for
+    /// [`end_hold`](Self::end_hold).
+    #[inline]
+    pub(crate) fn start_hold(&self) -> HoldStart {
+        HoldStart {
+            #[cfg(feature = "profiling")]
+            at: Instant::now(),
+        }
+    }
+
+    #[inline]
+    #[cfg_attr(not(feature = "profiling"), allow(unused_variables))]
+    pub(crate) fn end_hold(&self, start: HoldStart) {
+        #[cfg(feature = "profiling")]
+        if let Some(profile) = self.profile.get() {
+            profile.hold.record(nanos(start.at.elapsed()));
+        }
+    }
+
+    #[cfg(feature = "profiling")]
+    fn get(&self, kind: Kind, name: Option<&str>) -> &Profile {
+        self.profile.get_or_init(|| {
+            static NEXT: AtomicU64 = AtomicU64::new(1);
+            let named = name.is_some();
+            let name = match name {
+                Some(name) => name.to_string(),
+                None => format!("{kind} #{}", NEXT.fetch_add(1, Ordering::Relaxed)),
+            };
+            let profile = Arc::new(Profile {
+                name,
+                named,
+                kind,
+                acquisitions: AtomicU64::new(0),
+                contended: AtomicU64::new(0),
+                timeouts: AtomicU64::new(0),
+                cancellations: AtomicU64::new(0),
+                wait: Histogram::new(),
+                hold: Histogram::new(),
+            });
+            let mut profiles = PROFILES.lock();
+            profiles.retain(|p| p.strong_count() > 0);
+            profiles.push(Arc::downgrade(&profile));
+            profile
+        })
+    }
+}
+
+/// When a hold started. Zero-sized without the `profiling` feature.
+#[derive(Clone, Copy)]
+pub(crate) struct HoldStart {
+    #[cfg(feature = "profiling")]
+    at: Instant,
+}
+
+#[cfg(feature = "profiling")]
+pub(crate) fn nanos(d: StdDuration) -> u64 {
+    d.as_nanos().min(u64::MAX as u128) as u64
+}
+
+#[cfg(feature = "profiling")]
+static PROFILES: crate::nopoison::Mutex<Vec<Weak<Profile>>> =
+    crate::nopoison::Mutex::new(Vec::new());
+
+#[cfg(feature = "profiling")]
+pub(crate) struct Profile {
+    pub(crate) name: String,
+    /// Whether the lock was created with `named`.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub(crate) named: bool,
+    pub(crate) kind: Kind,
+    pub(crate) acquisitions: AtomicU64,
+    pub(crate) contended: AtomicU64,
+    /// Condition variable waits that reached their deadline.
+    pub(crate) timeouts: AtomicU64,
+    /// Condition variable waits cut short by a cancellation note.
+    pub(crate) cancellations: AtomicU64,
+    pub(crate) wait: Histogram,
+    pub(crate) hold: Histogram,
+}
+
+/// Returns the profiles of all live locks and condition variables.
+#[cfg(feature = "profiling")]
+pub(crate) fn live_profiles() -> Vec<Arc<Profile>> {
+    PROFILES.lock().iter().filter_map(Weak::upgrade).collect()
+}
+
+/// A lock-free histogram of nanosecond durations with power-of-two buckets.
+#[cfg(feature = "profiling")]
+pub(crate) struct Histogram {
+    buckets: [AtomicU64; 64],
+    count: AtomicU64,
+    sum: AtomicU64,
+    max: AtomicU64,
+}
+
+#[cfg(feature = "profiling")]
+impl Histogram {
+    pub(crate) fn new() -> Histogram {
+        Histogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, nanos: u64) {
+        // Bucket `b` holds values below 2^b.
+        let bucket = (u64::BITS - nanos.leading_zeros()).min(63) as usize;
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(nanos, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {
+        for bucket in &self.buckets {
+            bucket.store(0, Ordering::Relaxed);
+        }
+        self.count.store(0, Ordering::Relaxed);
+        self.sum.store(0, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+    }
+
+    /// Returns, for each bucket `b`, the number of recorded durations below
+    /// `2^b` nanoseconds. The counts are read once, so they are consistent
+    /// with each other even while durations are being recorded.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub(crate) fn cumulative_counts(&self) -> [u64; 64] {
+        let mut total = 0;
+        std::array::from_fn(|b| {
+            total += self.buckets[b].load(Ordering::Relaxed);
+            total
+        })
+    }
+
+    /// The total of all recorded durations, in nanoseconds.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub(crate) fn sum(&self) -> u64 {
+        self.sum.load(Ordering::Relaxed)
+    }
+
+    fn summary(&self) -> Summary {
+        let count = self.count.load(Ordering::Relaxed);
+        let max = self.max.load(Ordering::Relaxed);
+        let percentile = |p: f64| {
+            let target = ((count as f64 * p).ceil() as u64).max(1);
+            let mut seen = 0;
+            for (b, bucket) in self.buckets.iter().enumerate() {
+                seen += bucket.load(Ordering::Relaxed);
+                if seen >= target {
+                    let upper = if b == 0 { 0 } else { (1u64 << b) - 1 };
+                    return StdDuration::from_nanos(upper.min(max));
+                }
+            }
+            StdDuration::from_nanos(max)
+        };
+        Summary {
+            count,
+            p50: percentile(0.50),
+            p99: percentile(0.99),
+            max: StdDuration::from_nanos(max),
+        }
+    }
+}
+
+/// Summary statistics of a duration histogram.
+///
+/// Percentiles are upper bounds: durations are bucketed by powers of two.
+#[cfg(feature = "profiling")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    /// The number of recorded durations.
+    pub count: u64,
+    /// The median duration.
+    pub p50: StdDuration,
+    /// The 99th percentile duration.
+    pub p99: StdDuration,
+    /// The longest duration.
+    pub max: StdDuration,
+}
+
+/// The profile of one lock or condition variable.
+#[cfg(feature = "profiling")]
+#[derive(Debug, Clone)]
+pub struct LockProfile {
+    /// The lock's name if it was created with `named`, otherwise its kind and
+    /// a sequence number.
+    pub name: String,
+    /// `"Mutex"`, `"RwLock"` or `"Condvar"`.
+    pub kind: &'static str,
+    /// Blocking acquisitions, or waits for a condition variable.
+    pub acquisitions: u64,
+    /// Blocking acquisitions that found the lock held.
+    pub contended: u64,
+    /// Condition variable waits that reached their deadline.
+    pub timeouts: u64,
+    /// Condition variable waits cut short by a cancellation note.
+    pub cancellations: u64,
+    /// Time spent blocked acquiring the lock, or waiting on the condition
+    /// variable.
+    pub wait: Summary,
+    /// Time the lock was held. Empty for condition variables.
+    pub hold: Summary,
+}
+
+/// Returns the profiles of all live locks that have been acquired at least
+/// once, most contended first.
+#[cfg(feature = "profiling")]
+pub fn report() -> Vec<LockProfile> {
+    let profiles = live_profiles();
+    let mut report: Vec<LockProfile> = profiles
+        .iter()
+        .map(|p| LockProfile {
+            name: p.name.clone(),
+            kind: p.kind.as_str(),
+            acquisitions: p.acquisitions.load(Ordering::Relaxed),
+            contended: p.contended.load(Ordering::Relaxed),
+            timeouts: p.timeouts.load(Ordering::Relaxed),
+            cancellations: p.cancellations.load(Ordering::Relaxed),
+            wait: p.wait.summary(),
+            hold: p.hold.summary(),
+        })
+        .collect();
+    report.sort_by_key(|p| std::cmp::Reverse(p.contended));
+    report
+}
+
+/// Clears the statistics of every live lock, and any sampled contention
+/// stacks.
+#[cfg(feature = "profiling")]
+pub fn reset() {
+    #[cfg(feature = "contention-profile")]
+    crate::contention::reset();
+    for profile in PROFILES.lock().iter().filter_map(Weak::upgrade) {
+        profile.acquisitions.store(0, Ordering::Relaxed);
+        profile.contended.store(0, Ordering::Relaxed);
+        profile.timeouts.store(0, Ordering::Relaxed);
+        profile.cancellations.store(0, Ordering::Relaxed);
+        profile.wait.reset();
+        profile.hold.reset();
+    }
+}
@@ -0,0 +1,128 @@
+#[cfg(debug_assertions)]
+use std::cell::RefCell;
+
+/// A position in a lock hierarchy.
+///
+/// A thread may only block on a ranked lock whose level is strictly greater
+/// than the level of every ranked lock it already holds. Debug builds panic
+/// on a violation, naming both locks; release builds do not check.
+///
+/// ```
+/// use nsync_rs::{LockRank, Mutex};
+///
+/// const ACCOUNTS: LockRank = LockRank::new(10, "accounts");
+/// const LEDGER: LockRank = LockRank::new(20, "ledger");
+///
+/// let accounts = Mutex::with_rank(ACCOUNTS, 0);
+/// let ledger = Mutex::with_rank(LEDGER, 0);
+///
+/// let _a = accounts.lock().unwrap();
+/// let _l = ledger.lock().unwrap(); // fine: 20 > 10
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockRank {
+    level: u32,
+    name: &'static str,
+}
+
+impl LockRank {
+    /// Creates a rank with the given level and a name used in diagnostics.
+    pub const fn new(level: u32, name: &'static str) -> LockRank {
+        LockRank { level, name }
+    }
+
+    /// Returns the level of this rank.
+    pub fn level(&self) -> u32 {
+        self.level
+    }
+
+    /// Returns the name of this rank.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+#[cfg(debug_assertions)]
+thread_local! {
+    static HELD: RefCell<Vec<LockRank>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Panics if the current thread holds a lock ranked at or above `rank`.
+#[cfg(debug_assertions)]
+pub(crate) fn check(rank: LockRank) {
+    let conflict = HELD
+        .try_with(|held| {
+            held.borrow()
+                .iter()
+                .copied()
+                .filter(|h| h.level >= rank.level)
+                .max_by_key(|h| h.level)
+        })
+        .ok()
+        .flatten();
+    if let Some(held) = conflict {
+        panic!(
+            "lock hierarchy violation: acquiring `{}` (rank {}) while holding `{}` (rank {})",
+            rank.name, rank.level, held.name, held.level
+        );
+    }
+}
+
+#[cfg(debug_assertions)]
+pub(crate) fn acquired(rank: LockRank) {
+    let _ = HELD.try_with(|held| held.borrow_mut().push(rank));
+}
+
+#[cfg(debug_assertions)]
+pub(crate) fn released(rank: LockRank) {
+    let _ = HELD.try_with(|held| {
+        let mut held = held.borrow_mut();
+        if let Some(pos) = held.iter().rposition(|&h| h == rank) {
+            held.remove(pos);
+        }
+    });
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use super::*;
+    use crate::{Mutex, RwLock};
+
+    const LOW: LockRank = LockRank::new(10, "low");
+    const HIGH: LockRank = LockRank::new(20, "high");
+
+    #[test]
+    fn ascending_ranks_are_allowed() {
+        let low = Mutex::with_rank(LOW, ());
+        let high = RwLock::with_rank(HIGH, ());
+        let _low = low.lock().unwrap();
+        let _high = high.read().unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "acquiring `low` (rank 10) while holding `high` (rank 20)")]
+    fn descending_ranks_panic() {
+        let low = Mutex::with_rank(LOW, ());
+        let high = Mutex::with_rank(HIGH, ());
+        let _high = high.lock().unwrap();
+        let _low = low.lock().unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "lock hierarchy violation")]
+    fn equal_ranks_panic() {
+        let a = Mutex::with_rank(LOW, ());
+        let b = RwLock::with_rank(LOW, ());
+        let _a = a.lock().unwrap();
+        let _b = b.write().unwrap();
+    }
+
+    #[test]
+    fn released_locks_no_longer_constrain() {
+        let low = Mutex::with_rank(LOW, ());
+        let high = Mutex::with_rank(HIGH, ());
+        drop(high.lock().unwrap());
+        let _low = low.lock().unwrap();
+        let _high = high.lock().unwrap();
+    }
+}
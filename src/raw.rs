@@ -0,0 +1,144 @@
+use crate::ffi;
+use crate::mutex::NSYNC_MU_INIT;
+use crate::time::{Duration, Time};
+use std::cell::UnsafeCell;
+use std::time::Duration as StdDuration;
+
+/// A raw `nsync_mu` implementing [`lock_api::RawMutex`].
+///
+/// This lets nsync back `lock_api::Mutex` and anything else generic over
+/// `lock_api`, including mapped and arc guards.
+pub struct RawMutex {
+    inner: UnsafeCell<ffi::nsync_mu>,
+}
+
+unsafe impl Send for RawMutex {}
+unsafe impl Sync for RawMutex {}
+
+unsafe impl lock_api::RawMutex for RawMutex {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: RawMutex = RawMutex {
+        inner: UnsafeCell::new(NSYNC_MU_INIT),
+    };
+
+    type GuardMarker = lock_api::GuardNoSend;
+
+    fn lock(&self) {
+        unsafe { ffi::nsync_mu_lock(self.inner.get()) }
+    }
+
+    fn try_lock(&self) -> bool {
+        unsafe { ffi::nsync_mu_trylock(self.inner.get()) != 0 }
+    }
+
+    unsafe fn unlock(&self) {
+        unsafe { ffi::nsync_mu_unlock(self.inner.get()) }
+    }
+}
+
+unsafe impl lock_api::RawMutexTimed for RawMutex {
+    type Duration = StdDuration;
+    type Instant = Time;
+
+    fn try_lock_for(&self, timeout: StdDuration) -> bool {
+        self.try_lock_until(Time::now() + Duration::from(timeout))
+    }
+
+    fn try_lock_until(&self, deadline: Time) -> bool {
+        // nsync has no timed acquire, so poll with back-off until the deadline.
+        try_until(deadline, || unsafe {
+            ffi::nsync_mu_trylock(self.inner.get()) != 0
+        })
+    }
+}
+
+/// A raw `nsync_mu` implementing [`lock_api::RawRwLock`].
+///
+/// nsync cannot turn a write lock into a read lock, so `RawRwLockDowngrade`
+/// is not implemented.
+pub struct RawRwLock {
+    inner: UnsafeCell<ffi::nsync_mu>,
+}
+
+unsafe impl Send for RawRwLock {}
+unsafe impl Sync for RawRwLock {}
+
+unsafe impl lock_api::RawRwLock for RawRwLock {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: RawRwLock = RawRwLock {
+        inner: UnsafeCell::new(NSYNC_MU_INIT),
+    };
+
+    type GuardMarker = lock_api::GuardNoSend;
+
+    fn lock_shared(&self) {
+        unsafe { ffi::nsync_mu_rlock(self.inner.get()) }
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        unsafe { ffi::nsync_mu_rtrylock(self.inner.get()) != 0 }
+    }
+
+    unsafe fn unlock_shared(&self) {
+        unsafe { ffi::nsync_mu_runlock(self.inner.get()) }
+    }
+
+    fn lock_exclusive(&self) {
+        unsafe { ffi::nsync_mu_lock(self.inner.get()) }
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        unsafe { ffi::nsync_mu_trylock(self.inner.get()) != 0 }
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        unsafe { ffi::nsync_mu_unlock(self.inner.get()) }
+    }
+}
+
+unsafe impl lock_api::RawRwLockTimed for RawRwLock {
+    type Duration = StdDuration;
+    type Instant = Time;
+
+    fn try_lock_shared_for(&self, timeout: StdDuration) -> bool {
+        self.try_lock_shared_until(Time::now() + Duration::from(timeout))
+    }
+
+    fn try_lock_shared_until(&self, deadline: Time) -> bool {
+        try_until(deadline, || unsafe {
+            ffi::nsync_mu_rtrylock(self.inner.get()) != 0
+        })
+    }
+
+    fn try_lock_exclusive_for(&self, timeout: StdDuration) -> bool {
+        self.try_lock_exclusive_until(Time::now() + Duration::from(timeout))
+    }
+
+    fn try_lock_exclusive_until(&self, deadline: Time) -> bool {
+        try_until(deadline, || unsafe {
+            ffi::nsync_mu_trylock(self.inner.get()) != 0
+        })
+    }
+}
+
+/// Retries `try_lock` with exponential back-off until it succeeds or
+/// `deadline` passes.
+fn try_until(deadline: Time, try_lock: impl Fn() -> bool) -> bool {
+    let mut spins = 1u32;
+    loop {
+        if try_lock() {
+            return true;
+        }
+        if Time::now() >= deadline {
+            return false;
+        }
+        if spins <= 64 {
+            for _ in 0..spins {
+                std::hint::spin_loop();
+            }
+            spins *= 2;
+        } else {
+            std::thread::yield_now();
+        }
+    }
+}
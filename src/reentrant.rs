@@ -0,0 +1,136 @@
+use crate::ffi;
+use crate::mutex::NSYNC_MU_INIT;
+use crate::track::current_thread;
+use std::cell::UnsafeCell;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// A mutex which can be recursively locked by a single thread.
+///
+/// The owning thread can call [`lock`](ReentrantMutex::lock) again while it
+/// already holds the mutex; the mutex is released once every guard has been
+/// dropped. Since several guards may exist at once, they only give shared
+/// access to the data. Use a `Cell` or `RefCell` inside for mutation.
+pub struct ReentrantMutex<T: ?Sized> {
+    inner: UnsafeCell<ffi::nsync_mu>,
+    owner: AtomicUsize,
+    lock_count: UnsafeCell<u32>,
+    data: T,
+}
+
+unsafe impl<T: ?Sized + Send> Send for ReentrantMutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for ReentrantMutex<T> {}
+
+/// An RAII implementation of a "scoped lock" of a [`ReentrantMutex`].
+pub struct ReentrantMutexGuard<'a, T: ?Sized + 'a> {
+    lock: &'a ReentrantMutex<T>,
+    // !Send
+    _marker: PhantomData<*const ()>,
+}
+
+unsafe impl<T: ?Sized + Sync> Sync for ReentrantMutexGuard<'_, T> {}
+
+impl<T> ReentrantMutex<T> {
+    /// Creates a new reentrant mutex in an unlocked state ready for use.
+    pub const fn new(t: T) -> ReentrantMutex<T> {
+        ReentrantMutex {
+            inner: UnsafeCell::new(NSYNC_MU_INIT),
+            owner: AtomicUsize::new(0),
+            lock_count: UnsafeCell::new(0),
+            data: t,
+        }
+    }
+
+    /// Consumes this mutex, returning the underlying data.
+    pub fn into_inner(self) -> T {
+        self.data
+    }
+}
+
+impl<T: ?Sized> ReentrantMutex<T> {
+    /// Acquires the mutex, blocking the current thread until it is able to do
+    /// so. Returns immediately if the current thread already holds it.
+    pub fn lock(&self) -> ReentrantMutexGuard<'_, T> {
+        let this_thread = current_thread();
+        // Only this thread can have stored its own id, so a relaxed load is
+        // enough to tell whether we already own the mutex.
+        if self.owner.load(Ordering::Relaxed) == this_thread {
+            self.increment_lock_count();
+        } else {
+            unsafe {
+                ffi::nsync_mu_lock(self.inner.get());
+            }
+            self.owner.store(this_thread, Ordering::Relaxed);
+            unsafe {
+                *self.lock_count.get() = 1;
+            }
+        }
+        ReentrantMutexGuard {
+            lock: self,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Attempts to acquire the mutex without blocking.
+    pub fn try_lock(&self) -> Option<ReentrantMutexGuard<'_, T>> {
+        let this_thread = current_thread();
+        if self.owner.load(Ordering::Relaxed) == this_thread {
+            self.increment_lock_count();
+        } else if unsafe { ffi::nsync_mu_trylock(self.inner.get()) } != 0 {
+            self.owner.store(this_thread, Ordering::Relaxed);
+            unsafe {
+                *self.lock_count.get() = 1;
+            }
+        } else {
+            return None;
+        }
+        Some(ReentrantMutexGuard {
+            lock: self,
+            _marker: PhantomData,
+        })
+    }
+
+    /// Returns a mutable reference to the underlying data.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.data
+    }
+
+    fn increment_lock_count(&self) {
+        // Safety: only the owning thread touches `lock_count`.
+        unsafe {
+            let count = &mut *self.lock_count.get();
+            *count = count
+                .checked_add(1)
+                .expect("lock count overflow in reentrant mutex");
+        }
+    }
+}
+
+impl<T: Default> Default for ReentrantMutex<T> {
+    fn default() -> ReentrantMutex<T> {
+        ReentrantMutex::new(T::default())
+    }
+}
+
+impl<T: ?Sized> Deref for ReentrantMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.lock.data
+    }
+}
+
+impl<T: ?Sized> Drop for ReentrantMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Safety: the guard proves this thread owns the mutex.
+        unsafe {
+            let count = &mut *self.lock.lock_count.get();
+            *count -= 1;
+            if *count == 0 {
+                self.lock.owner.store(0, Ordering::Relaxed);
+                ffi::nsync_mu_unlock(self.lock.inner.get());
+            }
+        }
+    }
+}
@@ -0,0 +1,371 @@
+//! An opt-in registry of named locks, for dumping lock state when a process
+//! hangs.
+//!
+//! Named locks record which threads hold them and which are blocked on them.
+//! Threads record this cheaply as they lock, unlock and wait, and the dump
+//! formats it. The dump only reads a lock's nsync state while some thread is
+//! blocked on it: that thread borrows the lock, so it is alive and in place.
+
+use crate::debug;
+use crate::ffi;
+use crate::nopoison;
+use crate::time::Time;
+use crate::track::current_thread;
+use crate::watchdog::{self, Stall, StallKind};
+use std::backtrace::Backtrace;
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::{Arc, Weak};
+use std::thread::{self, Thread};
+use std::time::Duration as StdDuration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Mutex,
+    RwLock,
+    Condvar,
+}
+
+impl Kind {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Kind::Mutex => "Mutex",
+            Kind::RwLock => "RwLock",
+            Kind::Condvar => "Condvar",
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A named lock's registration. The lock owns it; the registry only keeps a
+/// weak reference, so dropping the lock unregisters it.
+pub(crate) struct Entry {
+    name: String,
+    kind: Kind,
+    state: nopoison::Mutex<State>,
+}
+
+struct State {
+    holder: Option<Holder>,
+    /// Threads holding the lock in shared mode, oldest first.
+    readers: Vec<Reader>,
+    /// Threads blocked acquiring the lock, or waiting on the condition
+    /// variable.
+    waiters: Vec<Waiter>,
+}
+
+struct Holder {
+    thread: Thread,
+    since: Time,
+    /// Where the lock was acquired; only captured while a watchdog runs.
+    backtrace: Option<Arc<Backtrace>>,
+    reported: bool,
+}
+
+/// A shared hold. A thread holding the lock for reading more than once has
+/// one per hold.
+struct Reader {
+    key: usize,
+    thread: Thread,
+    since: Time,
+    backtrace: Option<Arc<Backtrace>>,
+    reported: bool,
+}
+
+struct Waiter {
+    key: usize,
+    thread: Thread,
+    /// The address of the nsync object the thread is blocked on.
+    object: usize,
+    since: Time,
+    reported: bool,
+}
+
+/// Describes `thread` by name and id.
+fn describe(thread: &Thread) -> String {
+    match thread.name() {
+        Some(name) => format!("'{name}' ({:?})", thread.id()),
+        None => format!("{:?}", thread.id()),
+    }
+}
+
+static REGISTRY: nopoison::Mutex<Vec<Weak<Entry>>> = nopoison::Mutex::new(Vec::new());
+
+/// Creates and registers an entry for a lock called `name`.
+pub(crate) fn register(name: String, kind: Kind) -> Arc<Entry> {
+    let entry = Arc::new(Entry {
+        name,
+        kind,
+        state: nopoison::Mutex::new(State {
+            holder: None,
+            readers: Vec::new(),
+            waiters: Vec::new(),
+        }),
+    });
+    let mut registry = REGISTRY.lock();
+    registry.retain(|e| e.strong_count() > 0);
+    registry.push(Arc::downgrade(&entry));
+    entry
+}
+
+impl Entry {
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Runs `f`, which blocks acquiring the lock or waiting on the condition
+    /// variable whose nsync object is at `object`. The current thread is
+    /// listed as a waiter until `f` returns or unwinds, so `object` must
+    /// stay valid and in place until then.
+    pub(crate) fn waiting<R>(&self, object: usize, f: impl FnOnce() -> R) -> R {
+        struct Listed<'a>(&'a Entry, usize);
+
+        impl Drop for Listed<'_> {
+            fn drop(&mut self) {
+                self.0.state.lock().waiters.retain(|w| w.key != self.1);
+            }
+        }
+
+        let key = current_thread();
+        self.state.lock().waiters.push(Waiter {
+            key,
+            thread: thread::current(),
+            object,
+            since: Time::now(),
+            reported: false,
+        });
+        let _listed = Listed(self, key);
+        f()
+    }
+
+    /// Records a shared hold by the current thread.
+    pub(crate) fn add_reader(&self) {
+        let backtrace = watchdog::is_running().then(|| Arc::new(Backtrace::force_capture()));
+        self.state.lock().readers.push(Reader {
+            key: current_thread(),
+            thread: thread::current(),
+            since: Time::now(),
+            backtrace,
+            reported: false,
+        });
+    }
+
+    /// Forgets the current thread's latest shared hold, just before it is
+    /// released.
+    pub(crate) fn remove_reader(&self) {
+        let key = current_thread();
+        let mut state = self.state.lock();
+        if let Some(i) = state.readers.iter().rposition(|r| r.key == key) {
+            state.readers.remove(i);
+        }
+    }
+
+    /// Records the current thread as the exclusive holder.
+    pub(crate) fn set_holder(&self) {
+        let backtrace = watchdog::is_running().then(|| Arc::new(Backtrace::force_capture()));
+        self.state.lock().holder = Some(Holder {
+            thread: thread::current(),
+            since: Time::now(),
+            backtrace,
+            reported: false,
+        });
+    }
+
+    pub(crate) fn clear_holder(&self) {
+        self.state.lock().holder = None;
+    }
+
+    /// Appends to `stalls` each holder, reader and waiter that has exceeded
+    /// its limit and not been reported yet.
+    pub(crate) fn find_stalls(
+        &self,
+        hold_limit: StdDuration,
+        wait_limit: StdDuration,
+        stalls: &mut Vec<Stall>,
+    ) {
+        let now = Time::now();
+        let mut state = self.state.lock();
+        if let Some(holder) = state.holder.as_mut().filter(|h| !h.reported) {
+            let duration = StdDuration::from(now - holder.since);
+            if duration > hold_limit {
+                holder.reported = true;
+                stalls.push(Stall {
+                    lock: self.name.clone(),
+                    kind: StallKind::Held,
+                    thread: describe(&holder.thread),
+                    duration,
+                    backtrace: holder.backtrace.clone(),
+                });
+            }
+        }
+        for reader in state.readers.iter_mut().filter(|r| !r.reported) {
+            let duration = StdDuration::from(now - reader.since);
+            if duration > hold_limit {
+                reader.reported = true;
+                stalls.push(Stall {
+                    lock: self.name.clone(),
+                    kind: StallKind::ReadHeld,
+                    thread: describe(&reader.thread),
+                    duration,
+                    backtrace: reader.backtrace.clone(),
+                });
+            }
+        }
+        // Long waits on a condition variable are normal.
+        if self.kind == Kind::Condvar {
+            return;
+        }
+        for waiter in state.waiters.iter_mut().filter(|w| !w.reported) {
+            let duration = StdDuration::from(now - waiter.since);
+            if duration > wait_limit {
+                waiter.reported = true;
+                stalls.push(Stall {
+                    lock: self.name.clone(),
+                    kind: StallKind::Waiting,
+                    thread: describe(&waiter.thread),
+                    duration,
+                    backtrace: None,
+                });
+            }
+        }
+    }
+
+    fn dump(&self, out: &mut dyn Write) -> io::Result<()> {
+        let now = Time::now();
+        let state = self.state.lock();
+        write!(out, "{} \"{}\"", self.kind, self.name)?;
+        if state.holder.is_none() && state.readers.is_empty() && state.waiters.is_empty() {
+            return writeln!(out, ": idle");
+        }
+        if let Some(holder) = &state.holder {
+            let held_for = StdDuration::from(now - holder.since);
+            write!(
+                out,
+                ": held by {} for {held_for:?}",
+                describe(&holder.thread)
+            )?;
+        } else if !state.readers.is_empty() {
+            write!(out, ": held by {} readers", state.readers.len())?;
+        }
+        writeln!(out)?;
+        for reader in &state.readers {
+            let held_for = StdDuration::from(now - reader.since);
+            writeln!(
+                out,
+                "    reader {} for {held_for:?}",
+                describe(&reader.thread)
+            )?;
+        }
+        for waiter in &state.waiters {
+            let waited = StdDuration::from(now - waiter.since);
+            writeln!(
+                out,
+                "    waiter {} for {waited:?}",
+                describe(&waiter.thread)
+            )?;
+        }
+        // Safety: a waiter is only removed, under `state`, once its blocking
+        // call has returned, so the object it is blocked on is still
+        // borrowed by that call.
+        let Some(waiter) = state.waiters.first() else {
+            return Ok(());
+        };
+        let raw = match self.kind {
+            Kind::Mutex | Kind::RwLock => debug::mu_state(waiter.object as *mut ffi::nsync_mu).raw,
+            Kind::Condvar => debug::cv_state(waiter.object as *mut ffi::nsync_cv).raw,
+        };
+        for line in raw.lines() {
+            writeln!(out, "    {line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the entries of all named locks that are still alive.
+pub(crate) fn live_entries() -> Vec<Arc<Entry>> {
+    REGISTRY.lock().iter().filter_map(Weak::upgrade).collect()
+}
+
+/// Writes the name, holder and waiters of every live named lock to `out`.
+///
+/// Only locks created with `Mutex::named`, `RwLock::named` or
+/// `Condvar::named` are listed. Locks no thread is currently using are
+/// reported as idle. For a lock with waiters, nsync's description of its
+/// state and wait queue follows the waiters.
+pub fn dump_all_locks(out: &mut dyn Write) -> io::Result<()> {
+    for entry in live_entries() {
+        entry.dump(out)?;
+    }
+    Ok(())
+}
+
+/// Dumps all named locks to stderr whenever the process receives `SIGQUIT`.
+///
+/// This replaces the default `SIGQUIT` action of terminating with a core
+/// dump. The dump runs on a background thread, since it allocates and takes
+/// locks, which a signal handler must not do.
+#[cfg(all(feature = "sigquit", unix))]
+pub fn dump_on_sigquit() -> io::Result<()> {
+    use std::sync::atomic::{AtomicI32, Ordering};
+
+    static WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+
+    extern "C" fn on_sigquit(_: libc::c_int) {
+        let fd = WRITE_FD.load(Ordering::Relaxed);
+        if fd >= 0 {
+            // write(2) is async-signal-safe; a full pipe just drops the byte.
+            unsafe { libc::write(fd, [0u8].as_ptr().cast(), 1) };
+        }
+    }
+
+    // Serialize setup, so that concurrent first calls install one pipe and
+    // one dump thread between them.
+    static SETUP: nopoison::Mutex<()> = nopoison::Mutex::new(());
+    let _setup = SETUP.lock();
+    if WRITE_FD.load(Ordering::Relaxed) >= 0 {
+        return Ok(());
+    }
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let [read_fd, write_fd] = fds;
+    unsafe { libc::fcntl(write_fd, libc::F_SETFL, libc::O_NONBLOCK) };
+
+    let spawned = std::thread::Builder::new()
+        .name("nsync-sigquit".into())
+        .spawn(move || {
+            let mut byte = 0u8;
+            while unsafe { libc::read(read_fd, (&mut byte as *mut u8).cast(), 1) } == 1 {
+                let _ = dump_all_locks(&mut io::stderr().lock());
+            }
+            unsafe { libc::close(read_fd) };
+        });
+    if let Err(err) = spawned {
+        unsafe {
+            libc::close(read_fd);
+            libc::close(write_fd);
+        }
+        return Err(err);
+    }
+
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = on_sigquit as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(libc::SIGQUIT, &action, std::ptr::null_mut()) != 0 {
+            let err = io::Error::last_os_error();
+            // Closing the write end stops the dump thread.
+            libc::close(write_fd);
+            return Err(err);
+        }
+    }
+    WRITE_FD.store(write_fd, Ordering::Relaxed);
+    Ok(())
+}
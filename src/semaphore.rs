@@ -0,0 +1,230 @@
+use crate::mutex::{Mutex, MutexGuard};
+use crate::note::{Note, WaitOutcome};
+use crate::time::Time;
+use std::fmt;
+use std::time::Duration as StdDuration;
+
+/// A counting semaphore, built on [`Mutex`] and nsync's conditional waits.
+///
+/// Acquirers wait with [`Mutex::lock_when_until`] for enough permits, so
+/// releasing permits needs no explicit wakeup. Waiters are not queued
+/// fairly: a request for many permits can be overtaken by smaller ones.
+///
+/// ```
+/// use nsync_rs::Semaphore;
+///
+/// let disk = Semaphore::new(4);
+/// let permit = disk.acquire(1);
+/// assert_eq!(disk.available_permits(), 3);
+/// drop(permit);
+/// assert!(disk.try_acquire(4).is_some());
+/// ```
+pub struct Semaphore {
+    permits: Mutex<usize>,
+}
+
+impl Semaphore {
+    /// Creates a semaphore with `permits` permits available.
+    pub const fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits: Mutex::new(permits),
+        }
+    }
+
+    /// Creates a semaphore with `permits` permits available, whose mutex is
+    /// registered under `name`.
+    pub fn named(name: impl Into<String>, permits: usize) -> Semaphore {
+        Semaphore {
+            permits: Mutex::named(name, permits),
+        }
+    }
+
+    /// Blocks until `n` permits are available, then takes them.
+    pub fn acquire(&self, n: usize) -> SemaphorePermit<'_> {
+        match self.acquire_until(n, Time::no_deadline(), None) {
+            Ok(permit) => permit,
+            Err(_) => unreachable!("semaphore wait without a deadline gave up"),
+        }
+    }
+
+    /// Takes `n` permits if they are available right now.
+    pub fn try_acquire(&self, n: usize) -> Option<SemaphorePermit<'_>> {
+        let mut permits = self.lock();
+        if *permits < n {
+            return None;
+        }
+        *permits -= n;
+        Some(SemaphorePermit {
+            semaphore: self,
+            permits: n,
+        })
+    }
+
+    /// Blocks until `n` permits are available, `deadline` passes or
+    /// `cancel` is notified, taking the permits only in the first case.
+    pub fn acquire_until(
+        &self,
+        n: usize,
+        deadline: Time,
+        cancel: Option<&Note>,
+    ) -> Result<SemaphorePermit<'_>, WaitOutcome> {
+        let enough = |&permits: &usize| permits >= n;
+        let (mut permits, outcome) = match self.permits.lock_when_until(enough, deadline, cancel) {
+            Ok(result) => result,
+            Err(err) => err.into_inner(),
+        };
+        if *permits < n {
+            return Err(outcome);
+        }
+        *permits -= n;
+        Ok(SemaphorePermit {
+            semaphore: self,
+            permits: n,
+        })
+    }
+
+    /// Blocks until `n` permits are available or `timeout` elapses.
+    pub fn acquire_timeout(
+        &self,
+        n: usize,
+        timeout: StdDuration,
+    ) -> Result<SemaphorePermit<'_>, WaitOutcome> {
+        self.acquire_until(n, Time::now() + timeout.into(), None)
+    }
+
+    /// Adds `n` permits, waking any acquirers they satisfy.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of available permits would overflow `usize`.
+    pub fn release(&self, n: usize) {
+        let mut permits = self.lock();
+        match permits.checked_add(n) {
+            Some(total) => *permits = total,
+            None => {
+                drop(permits);
+                panic!("Semaphore::release({n}) would overflow the number of available permits");
+            }
+        }
+    }
+
+    /// Returns the number of permits available right now.
+    pub fn available_permits(&self) -> usize {
+        *self.lock()
+    }
+
+    /// Locks the permit count. Nothing panics while it is held, so a
+    /// poisoned lock is still consistent.
+    fn lock(&self) -> MutexGuard<'_, usize> {
+        match self.permits.lock() {
+            Ok(guard) => guard,
+            Err(err) => err.into_inner(),
+        }
+    }
+}
+
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Semaphore")
+            .field("available_permits", &self.available_permits())
+            .finish()
+    }
+}
+
+/// Permits taken from a [`Semaphore`], returned when dropped.
+#[must_use = "dropping the permit immediately returns it to the semaphore"]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// Returns the number of permits held.
+    pub fn permits(&self) -> usize {
+        self.permits
+    }
+
+    /// Keeps the permits taken for good instead of returning them.
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.permits > 0 {
+            self.semaphore.release(self.permits);
+        }
+    }
+}
+
+impl fmt::Debug for SemaphorePermit<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SemaphorePermit")
+            .field("permits", &self.permits)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn permits_return_on_drop_and_forget_keeps_them() {
+        let semaphore = Semaphore::new(3);
+        let two = semaphore.acquire(2);
+        assert_eq!(two.permits(), 2);
+        assert_eq!(semaphore.available_permits(), 1);
+        assert!(semaphore.try_acquire(2).is_none());
+        drop(two);
+        assert_eq!(semaphore.available_permits(), 3);
+        semaphore.acquire(1).forget();
+        assert_eq!(semaphore.available_permits(), 2);
+    }
+
+    #[test]
+    fn release_wakes_a_blocked_acquirer() {
+        let semaphore = Arc::new(Semaphore::new(0));
+        let acquirer = {
+            let semaphore = Arc::clone(&semaphore);
+            thread::spawn(move || semaphore.acquire(2).forget())
+        };
+        semaphore.release(1);
+        semaphore.release(1);
+        acquirer.join().unwrap();
+        assert_eq!(semaphore.available_permits(), 0);
+    }
+
+    #[test]
+    fn acquire_until_times_out_without_taking_permits() {
+        let semaphore = Semaphore::new(1);
+        let deadline = Time::now() + StdDuration::from_millis(20).into();
+        let result = semaphore.acquire_until(2, deadline, None);
+        assert!(matches!(result, Err(WaitOutcome::TimedOut)));
+        assert_eq!(semaphore.available_permits(), 1);
+    }
+
+    #[test]
+    fn acquire_until_is_cancelled_by_its_note() {
+        let semaphore = Semaphore::new(0);
+        let cancel = Note::new(None, Time::no_deadline());
+        let result = thread::scope(|s| {
+            s.spawn(|| {
+                thread::sleep(StdDuration::from_millis(20));
+                cancel.notify();
+            });
+            semaphore.acquire_until(1, Time::no_deadline(), Some(&cancel))
+        });
+        assert!(matches!(result, Err(WaitOutcome::Cancelled)));
+        assert_eq!(semaphore.available_permits(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "would overflow")]
+    fn release_panics_on_overflow() {
+        Semaphore::new(usize::MAX).release(1);
+    }
+}
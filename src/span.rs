@@ -0,0 +1,59 @@
+//! `tracing` spans and events for blocking operations, enabled by the
+//! `tracing` feature.
+//!
+//! Contended lock acquisitions and waits on condition variables, notes and
+//! counters run inside a `DEBUG` span and end with an event carrying the
+//! object's name, how long the thread blocked and the outcome. Waits longer
+//! than [`set_slow_wait_threshold`] are reported at `WARN`.
+
+use crate::track::Outcome;
+#[cfg(feature = "tracing")]
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration as StdDuration, Instant},
+};
+
+#[cfg(feature = "tracing")]
+static SLOW_NANOS: AtomicU64 = AtomicU64::new(10_000_000);
+
+/// Sets how long a blocking operation may take before its event is raised
+/// from `DEBUG` to `WARN`. The default is 10ms.
+#[cfg(feature = "tracing")]
+pub fn set_slow_wait_threshold(threshold: StdDuration) {
+    SLOW_NANOS.store(
+        threshold.as_nanos().min(u64::MAX as u128) as u64,
+        Ordering::Relaxed,
+    );
+}
+
+/// Runs the blocking operation `op` on the object described by `kind` and
+/// `name` inside a span, then emits an event with the wait and the outcome
+/// that `outcome` derives from the result.
+#[inline]
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn blocking<R>(
+    kind: &'static str,
+    name: Option<&str>,
+    op: &'static str,
+    f: impl FnOnce() -> R,
+    outcome: impl FnOnce(&R) -> Outcome,
+) -> R {
+    #[cfg(feature = "tracing")]
+    {
+        let span = tracing::debug_span!("nsync", kind, lock = name, op);
+        let _entered = span.enter();
+        let start = Instant::now();
+        let result = f();
+        let wait = start.elapsed();
+        let outcome = outcome(&result).as_str();
+        let wait_us = wait.as_micros() as u64;
+        if wait.as_nanos() >= SLOW_NANOS.load(Ordering::Relaxed) as u128 {
+            tracing::warn!(kind, lock = name, op, wait_us, outcome, "slow {op}");
+        } else {
+            tracing::debug!(kind, lock = name, op, wait_us, outcome, "{op}");
+        }
+        result
+    }
+    #[cfg(not(feature = "tracing"))]
+    f()
+}
@@ -4,11 +4,11 @@ use std::ops::{Add, Sub};
 use std::time::Duration as StdDuration;
 
 /// A point in time
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
 pub struct Time(pub(super) ffi::nsync_time);
 
 /// A duration of time
-#[derive(Copy, Clone)]
+#[derive(Debug, Copy, Clone)]
 pub struct Duration(ffi::nsync_time);
 impl Eq for Time {}
 
@@ -115,6 +115,16 @@ impl From<StdDuration> for Duration {
     }
 }
 
+impl From<Duration> for StdDuration {
+    /// Converts to a standard duration, clamping negative durations to zero.
+    fn from(d: Duration) -> Self {
+        if d.0.tv_sec < 0 {
+            return StdDuration::ZERO;
+        }
+        StdDuration::new(d.0.tv_sec as u64, d.0.tv_nsec as u32)
+    }
+}
+
 impl Add<Duration> for Time {
     type Output = Time;
 
@@ -0,0 +1,345 @@
+use crate::checked::{self, CondvarMutex};
+use crate::deadlock::{self, LockId};
+use crate::events::{self, Event};
+use crate::ffi;
+use crate::note::WaitOutcome;
+use crate::profiling::{HoldStart, ProfileSlot};
+#[cfg(debug_assertions)]
+use crate::rank;
+use crate::rank::LockRank;
+use crate::registry::{self, Entry, Kind};
+use crate::span;
+use std::io;
+use std::os::raw::c_int;
+use std::sync::Arc;
+#[cfg(feature = "owner-tracking")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Returns a nonzero identifier unique to each live thread.
+pub(crate) fn current_thread() -> usize {
+    thread_local! {
+        static ID: u8 = const { 0 };
+    }
+    ID.with(|id| id as *const u8 as usize)
+}
+
+/// How a blocking operation ended.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+pub(crate) enum Outcome {
+    Acquired,
+    Woken,
+    TimedOut,
+    Cancelled,
+}
+
+impl Outcome {
+    /// The outcome of a wait that may have timed out.
+    pub(crate) fn waited(timed_out: bool) -> Outcome {
+        if timed_out {
+            Outcome::TimedOut
+        } else {
+            Outcome::Woken
+        }
+    }
+
+    /// The outcome of an nsync wait with a deadline and cancellation note,
+    /// from its result: 0, `ETIMEDOUT` or `ECANCELED`.
+    pub(crate) fn cancellable(result: c_int) -> Outcome {
+        match result {
+            0 => Outcome::Woken,
+            r if io::Error::from_raw_os_error(r).kind() == io::ErrorKind::TimedOut => {
+                Outcome::TimedOut
+            }
+            _ => Outcome::Cancelled,
+        }
+    }
+
+    /// The public form of a wait's outcome.
+    pub(crate) fn wait_outcome(self) -> WaitOutcome {
+        match self {
+            Outcome::Acquired | Outcome::Woken => WaitOutcome::Completed,
+            Outcome::TimedOut => WaitOutcome::TimedOut,
+            Outcome::Cancelled => WaitOutcome::Cancelled,
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Acquired => "acquired",
+            Outcome::Woken => "woken",
+            Outcome::TimedOut => "timed_out",
+            Outcome::Cancelled => "cancelled",
+        }
+    }
+}
+
+/// Per-lock bookkeeping for the debugging aids that watch acquisitions.
+///
+/// Locks call into this around every acquisition and release; each aid
+/// compiles to nothing unless it is enabled.
+pub(crate) struct Tracker {
+    kind: Kind,
+    id: LockId,
+    #[cfg(debug_assertions)]
+    rank: Option<LockRank>,
+    #[cfg(feature = "owner-tracking")]
+    owner: AtomicUsize,
+    entry: Option<Arc<Entry>>,
+    profile: ProfileSlot,
+}
+
+impl Tracker {
+    pub(crate) const fn new(kind: Kind) -> Tracker {
+        Tracker {
+            kind,
+            id: LockId::new(),
+            #[cfg(debug_assertions)]
+            rank: None,
+            #[cfg(feature = "owner-tracking")]
+            owner: AtomicUsize::new(0),
+            entry: None,
+            profile: ProfileSlot::new(),
+        }
+    }
+
+    #[cfg_attr(not(debug_assertions), allow(unused_variables))]
+    pub(crate) const fn ranked(kind: Kind, rank: LockRank) -> Tracker {
+        Tracker {
+            kind,
+            id: LockId::new(),
+            #[cfg(debug_assertions)]
+            rank: Some(rank),
+            #[cfg(feature = "owner-tracking")]
+            owner: AtomicUsize::new(0),
+            entry: None,
+            profile: ProfileSlot::new(),
+        }
+    }
+
+    /// Registers a lock called `name` for [`registry::dump_all_locks`].
+    pub(crate) fn named(name: String, kind: Kind) -> Tracker {
+        Tracker {
+            entry: Some(registry::register(name, kind)),
+            ..Tracker::new(kind)
+        }
+    }
+
+    /// Called before blocking to acquire the lock.
+    #[inline]
+    pub(crate) fn before_lock(&self) {
+        #[cfg(debug_assertions)]
+        if let Some(rank) = self.rank {
+            rank::check(rank);
+        }
+        deadlock::before_lock(&self.id, self.name());
+        self.trace(Event::LockRequested);
+    }
+
+    /// Acquires the lock, whose nsync mutex is `mu`, with `lock`, after
+    /// trying `try_lock` first when profiling or tracing needs to know
+    /// whether the lock is contended.
+    #[inline]
+    pub(crate) fn acquire(
+        &self,
+        mu: *mut ffi::nsync_mu,
+        try_lock: impl FnOnce() -> bool,
+        lock: impl FnOnce(),
+    ) {
+        let name = self.name();
+        let lock = || {
+            span::blocking(self.kind.as_str(), name, "lock", lock, |_| {
+                Outcome::Acquired
+            })
+        };
+        let acquire = || self.profile.acquire(self.kind, name, try_lock, lock);
+        match &self.entry {
+            Some(entry) => entry.waiting(mu as usize, acquire),
+            None => acquire(),
+        }
+    }
+
+    /// Counts an acquisition, announced with
+    /// [`before_lock`](Self::before_lock), that got the lock without
+    /// blocking, as [`lock_all`](crate::lock_all) does for all but one lock.
+    #[inline]
+    pub(crate) fn acquired_without_blocking(&self) {
+        let name = self.name();
+        self.profile.uncontended(self.kind, name);
+    }
+
+    /// Called once the current thread holds the lock.
+    #[inline]
+    pub(crate) fn acquired(&self) {
+        #[cfg(debug_assertions)]
+        if let Some(rank) = self.rank {
+            rank::acquired(rank);
+        }
+        deadlock::acquired(&self.id, self.name());
+        checked::acquired(self.addr());
+        self.trace(Event::LockAcquired);
+    }
+
+    /// Called just before the current thread releases the lock.
+    #[inline]
+    pub(crate) fn released(&self) {
+        self.trace(Event::LockReleased);
+        #[cfg(debug_assertions)]
+        if let Some(rank) = self.rank {
+            rank::released(rank);
+        }
+        deadlock::released(&self.id);
+        checked::released(self.addr());
+    }
+
+    /// Records the current thread as the exclusive holder of the lock.
+    #[inline]
+    pub(crate) fn set_owner(&self) {
+        #[cfg(feature = "owner-tracking")]
+        self.owner.store(current_thread(), Ordering::Relaxed);
+        if let Some(entry) = &self.entry {
+            entry.set_holder();
+        }
+        self.profile.begin_exclusive_hold();
+    }
+
+    /// Forgets the exclusive holder, just before the lock is released.
+    #[inline]
+    pub(crate) fn clear_owner(&self) {
+        #[cfg(feature = "owner-tracking")]
+        self.owner.store(0, Ordering::Relaxed);
+        if let Some(entry) = &self.entry {
+            entry.clear_holder();
+        }
+        self.profile.end_exclusive_hold();
+    }
+
+    /// Called when a shared hold of the lock starts.
+    #[inline]
+    pub(crate) fn start_shared_hold(&self) -> HoldStart {
+        if let Some(entry) = &self.entry {
+            entry.add_reader();
+        }
+        self.profile.start_hold()
+    }
+
+    /// Called just before a shared hold of the lock is released.
+    #[inline]
+    pub(crate) fn end_shared_hold(&self, start: HoldStart) {
+        if let Some(entry) = &self.entry {
+            entry.remove_reader();
+        }
+        self.profile.end_hold(start);
+    }
+
+    /// The lock's registry name, if it is named.
+    #[inline]
+    fn name(&self) -> Option<&str> {
+        self.entry.as_ref().map(|e| e.name())
+    }
+
+    /// Identifies the lock while it is borrowed.
+    #[inline]
+    pub(crate) fn addr(&self) -> usize {
+        self as *const Tracker as usize
+    }
+
+    /// Records `event` for the event recorder.
+    #[inline]
+    fn trace(&self, event: Event) {
+        let object = self.addr();
+        events::record(event, object, || match &self.entry {
+            Some(entry) => entry.name().to_string(),
+            None => format!("{} {object:#x}", self.kind),
+        });
+    }
+
+    /// Returns whether the current thread holds the lock exclusively.
+    #[cfg(feature = "owner-tracking")]
+    pub(crate) fn is_owned_by_current_thread(&self) -> bool {
+        self.owner.load(Ordering::Relaxed) == current_thread()
+    }
+}
+
+/// Per-condition-variable bookkeeping, the counterpart of [`Tracker`].
+pub(crate) struct CondvarTracker {
+    entry: Option<Arc<Entry>>,
+    profile: ProfileSlot,
+    mutex: CondvarMutex,
+}
+
+impl CondvarTracker {
+    pub(crate) const fn new() -> CondvarTracker {
+        CondvarTracker {
+            entry: None,
+            profile: ProfileSlot::new(),
+            mutex: CondvarMutex::new(),
+        }
+    }
+
+    /// Registers a condition variable called `name` for
+    /// [`registry::dump_all_locks`].
+    pub(crate) fn named(name: String) -> CondvarTracker {
+        CondvarTracker {
+            entry: Some(registry::register(name, Kind::Condvar)),
+            ..CondvarTracker::new()
+        }
+    }
+
+    /// Called before waiting with the mutex identified by `mutex`, the
+    /// address of its [`Tracker`] or, for an untracked mutex, of its nsync
+    /// object, while the current thread still holds it.
+    #[inline]
+    pub(crate) fn before_wait(&self, mutex: usize) {
+        self.mutex.before_wait(mutex, || self.describe());
+    }
+
+    /// Runs `wait`, which blocks on the condition variable whose nsync
+    /// object is `cv`.
+    #[inline]
+    pub(crate) fn wait(&self, cv: *mut ffi::nsync_cv, wait: impl FnOnce() -> Outcome) -> Outcome {
+        self.trace(Event::CondvarWait);
+        let name = self.entry.as_ref().map(|e| e.name());
+        let wait = || span::blocking("Condvar", name, "wait", wait, |&outcome| outcome);
+        let wait = || self.profile.time_wait(Kind::Condvar, name, wait);
+        let result = match &self.entry {
+            Some(entry) => entry.waiting(cv as usize, wait),
+            None => wait(),
+        };
+        self.trace(Event::CondvarWoken);
+        self.mutex.after_wait();
+        result
+    }
+
+    /// Called when the condition variable is signalled, or broadcast if
+    /// `all` is set.
+    #[inline]
+    pub(crate) fn notified(&self, all: bool) {
+        self.trace(if all {
+            Event::CondvarBroadcast
+        } else {
+            Event::CondvarSignal
+        });
+    }
+
+    #[inline]
+    fn trace(&self, event: Event) {
+        let object = self as *const CondvarTracker as usize;
+        events::record(event, object, || self.name());
+    }
+
+    fn name(&self) -> String {
+        match &self.entry {
+            Some(entry) => entry.name().to_string(),
+            None => format!("Condvar {:#x}", self as *const CondvarTracker as usize),
+        }
+    }
+
+    fn describe(&self) -> String {
+        match &self.entry {
+            Some(entry) => format!("condition variable `{}`", entry.name()),
+            None => "a condition variable".to_string(),
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use crate::note::{Counter, WaitOutcome};
+use crate::time::Time;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration as StdDuration;
+
+/// Waits for a group of tasks to finish, built on [`Counter`].
+///
+/// Each task holds a [`WaitGroupToken`] from [`add`](WaitGroup::add), which
+/// marks the task finished when dropped, including when the task panics, so
+/// the count cannot leak.
+///
+/// nsync does not let a counter be raised from zero once it has been waited
+/// on, so once any wait has been made on a group, even one that timed out,
+/// tasks can only be added while others are still unfinished. Use a new
+/// `WaitGroup` for each batch.
+///
+/// ```
+/// use nsync_rs::WaitGroup;
+///
+/// let group = WaitGroup::new();
+/// for i in 0..4 {
+///     let token = group.add();
+///     std::thread::spawn(move || {
+///         let _token = token;
+///         println!("task {i}");
+///     });
+/// }
+/// group.wait();
+/// ```
+#[derive(Clone)]
+pub struct WaitGroup {
+    counter: Arc<Counter>,
+}
+
+impl WaitGroup {
+    /// Creates an empty group.
+    pub fn new() -> WaitGroup {
+        WaitGroup {
+            counter: Arc::new(Counter::new(0)),
+        }
+    }
+
+    /// Creates an empty group whose counter is called `name`.
+    pub fn named(name: impl Into<String>) -> WaitGroup {
+        WaitGroup {
+            counter: Arc::new(Counter::named(name, 0)),
+        }
+    }
+
+    /// Adds a task to the group, returning the token that marks it
+    /// finished when dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no task is unfinished and the group has been waited on, even
+    /// by a wait that timed out.
+    pub fn add(&self) -> WaitGroupToken {
+        if let Err(err) = self.counter.try_add(1) {
+            panic!("WaitGroup::add: {err}; use a new WaitGroup for each batch of tasks");
+        }
+        WaitGroupToken {
+            counter: self.counter.clone(),
+        }
+    }
+
+    /// Returns the number of unfinished tasks.
+    pub fn count(&self) -> u32 {
+        self.counter.value()
+    }
+
+    /// Blocks until every task has finished.
+    pub fn wait(&self) {
+        self.counter.wait(Time::no_deadline());
+    }
+
+    /// Blocks until every task has finished or `deadline` passes.
+    pub fn wait_until(&self, deadline: Time) -> WaitOutcome {
+        self.counter.wait_until(deadline)
+    }
+
+    /// Blocks until every task has finished or `timeout` elapses.
+    pub fn wait_timeout(&self, timeout: StdDuration) -> WaitOutcome {
+        self.counter.wait_timeout(timeout)
+    }
+}
+
+impl Default for WaitGroup {
+    fn default() -> WaitGroup {
+        WaitGroup::new()
+    }
+}
+
+impl fmt::Debug for WaitGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WaitGroup")
+            .field("count", &self.count())
+            .finish()
+    }
+}
+
+/// A task's membership in a [`WaitGroup`]. Dropping it marks the task
+/// finished.
+#[must_use = "dropping the token immediately marks the task finished"]
+pub struct WaitGroupToken {
+    counter: Arc<Counter>,
+}
+
+impl Drop for WaitGroupToken {
+    fn drop(&mut self) {
+        self.counter.add(-1);
+    }
+}
+
+impl fmt::Debug for WaitGroupToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WaitGroupToken").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{self, AssertUnwindSafe};
+    use std::thread;
+
+    #[test]
+    fn waits_for_every_task() {
+        let group = WaitGroup::new();
+        let tokens: Vec<_> = (0..4).map(|_| group.add()).collect();
+        assert_eq!(group.count(), 4);
+        thread::scope(|s| {
+            for token in tokens {
+                s.spawn(move || drop(token));
+            }
+            group.wait();
+        });
+        assert_eq!(group.count(), 0);
+    }
+
+    #[test]
+    fn a_panicking_task_still_finishes() {
+        let group = WaitGroup::new();
+        let token = group.add();
+        let task = thread::spawn(move || {
+            let _token = token;
+            panic!("task failed");
+        });
+        assert!(task.join().is_err());
+        assert_eq!(
+            group.wait_timeout(StdDuration::from_secs(5)),
+            WaitOutcome::Completed
+        );
+    }
+
+    #[test]
+    fn tasks_can_join_a_waited_group_only_while_others_are_unfinished() {
+        let group = WaitGroup::new();
+        let first = group.add();
+        assert_eq!(
+            group.wait_timeout(StdDuration::from_millis(1)),
+            WaitOutcome::TimedOut
+        );
+        let second = group.add();
+        drop((first, second));
+        group.wait();
+        let added = panic::catch_unwind(AssertUnwindSafe(|| group.add()));
+        let message = added.err().and_then(|p| p.downcast::<String>().ok());
+        assert!(message.is_some_and(|m| m.contains("use a new WaitGroup")));
+    }
+}
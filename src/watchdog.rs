@@ -0,0 +1,128 @@
+use crate::note::Note;
+use crate::registry;
+use crate::time::{Duration, Time};
+use std::backtrace::Backtrace;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration as StdDuration;
+
+/// The number of watchdogs currently running.
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns whether any watchdog is running, in which case named locks
+/// capture backtraces on acquisition.
+pub(crate) fn is_running() -> bool {
+    RUNNING.load(Ordering::Relaxed) != 0
+}
+
+/// Whether a stall was caused by holding a lock or waiting for one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StallKind {
+    /// A thread has held the lock exclusively for longer than the hold
+    /// limit.
+    Held,
+    /// A thread has held the lock for reading for longer than the hold
+    /// limit.
+    ReadHeld,
+    /// A thread has waited for the lock for longer than the wait limit.
+    Waiting,
+}
+
+/// A lock held or waited on for too long, as reported by a [`Watchdog`].
+#[derive(Debug, Clone)]
+pub struct Stall {
+    /// The name of the lock.
+    pub lock: String,
+    /// Whether the thread is holding, read-holding or waiting for the lock.
+    pub kind: StallKind,
+    /// The thread's name and id.
+    pub thread: String,
+    /// How long the thread has held or waited for the lock so far.
+    pub duration: StdDuration,
+    /// Where the holder acquired the lock. `None` for waiters.
+    pub backtrace: Option<Arc<Backtrace>>,
+}
+
+impl fmt::Display for Stall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = match self.kind {
+            StallKind::Held => "has held",
+            StallKind::ReadHeld => "has read-held",
+            StallKind::Waiting => "has waited for",
+        };
+        write!(
+            f,
+            "thread {} {} lock \"{}\" for {:?}",
+            self.thread, verb, self.lock, self.duration
+        )?;
+        if let Some(backtrace) = &self.backtrace {
+            write!(f, "; acquired at:\n{backtrace}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A background thread that reports named locks held, exclusively or for
+/// reading, or waited on for longer than a threshold.
+///
+/// Only locks created with `Mutex::named` or `RwLock::named` are watched.
+/// While a watchdog runs, every acquisition of a named lock captures a
+/// backtrace, which is slow. Each stall is reported once. The watchdog stops
+/// when dropped.
+pub struct Watchdog {
+    stop: Arc<Note>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watchdog {
+    /// Starts a watchdog that prints stalls to stderr.
+    pub fn new(hold_limit: StdDuration, wait_limit: StdDuration) -> Watchdog {
+        Watchdog::with_handler(hold_limit, wait_limit, |stall| {
+            eprintln!("nsync-rs watchdog: {stall}")
+        })
+    }
+
+    /// Starts a watchdog that calls `handler` for each stall.
+    pub fn with_handler<F>(hold_limit: StdDuration, wait_limit: StdDuration, handler: F) -> Watchdog
+    where
+        F: Fn(&Stall) + Send + 'static,
+    {
+        let stop = Arc::new(Note::new(None, Time::no_deadline()));
+        let interval = (hold_limit.min(wait_limit) / 4).max(StdDuration::from_millis(10));
+        RUNNING.fetch_add(1, Ordering::Relaxed);
+
+        let note = Arc::clone(&stop);
+        let thread = std::thread::Builder::new()
+            .name("nsync-watchdog".into())
+            .spawn(move || {
+                let mut stalls = Vec::new();
+                while !note.is_notified() {
+                    for entry in registry::live_entries() {
+                        entry.find_stalls(hold_limit, wait_limit, &mut stalls);
+                    }
+                    for stall in stalls.drain(..) {
+                        handler(&stall);
+                    }
+                    note.wait(Time::now() + Duration::from(interval));
+                }
+            })
+            .expect("failed to spawn watchdog thread");
+
+        Watchdog {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop.notify();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        RUNNING.fetch_sub(1, Ordering::Relaxed);
+    }
+}